@@ -0,0 +1,849 @@
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, Write};
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::sync::{Arc, LazyLock, RwLock, RwLockReadGuard};
+use zip::write::FileOptions;
+
+use crate::compress::{CompressOptions, DecompressError};
+use crate::diff::file_mode;
+use crate::limits::LimitTracker;
+use crate::progress::{Phase, PhaseProgress};
+
+/// Number of leading bytes read to recognise a format by its signature.
+pub const SNIFF_LEN: usize = 512;
+
+/// Kind of an entry yielded by an [`ArchiveReader`].
+#[derive(Debug, Clone)]
+pub enum EntryKind {
+    Dir,
+    File,
+    Symlink(String),
+    /// Hard link to an earlier entry, named relative to the archive root.
+    HardLink(String),
+}
+
+/// An archive entry whose path has already passed the extraction limits.
+#[derive(Debug, Clone)]
+pub struct ArchiveEntry {
+    pub name: String,
+    pub kind: EntryKind,
+    pub mode: Option<u32>,
+    /// CRC32 and uncompressed size from the zip central directory.
+    pub zip_meta: Option<(u32, u64)>,
+}
+
+pub type EntryVisitor<'v> =
+    dyn FnMut(&ArchiveEntry, &mut dyn Read) -> Result<(), DecompressError> + 'v;
+
+/// Reads the entries of one archive format.
+pub trait ArchiveReader: Send + Sync {
+    /// Walks every entry of the archive at `input_path` in order.
+    ///
+    /// Implementations pass each raw entry path through [`LimitTracker::entry_name`],
+    /// skip entries it maps to `None`, and hand file data to `visit` through
+    /// [`LimitTracker::reader`] so that every byte counts against the limits.
+    fn read(
+        &self,
+        input_path: &str,
+        tracker: &mut LimitTracker,
+        visit: &mut EntryVisitor,
+    ) -> Result<(), DecompressError>;
+
+    /// Uncompressed size of all entries, for formats that record it up front.
+    ///
+    /// Used to estimate the remaining time of progress reports; the default is unknown.
+    fn total_size(&self, _input_path: &str) -> Option<u64> {
+        None
+    }
+}
+
+/// Packs a directory into an archive of one format.
+pub trait ArchiveWriter: Send + Sync {
+    /// Checks the options against the format before the output file is created.
+    ///
+    /// The default rejects a compression method, which only zip patches take, and
+    /// long-distance matching, which only zstd-compressed tar patches take.
+    fn validate(&self, options: &CompressOptions) -> Result<(), DecompressError> {
+        reject_method(options)?;
+        reject_long_distance(options)
+    }
+
+    /// Writes the contents of the directory `input_path` to `output_path`.
+    fn write(
+        &self,
+        input_path: &str,
+        output_path: &str,
+        options: &CompressOptions,
+    ) -> Result<(), DecompressError>;
+}
+
+/// An archive format known to the [`FormatRegistry`].
+#[derive(Clone)]
+pub struct ArchiveFormat {
+    /// Canonical name, such as `tar.gz`.
+    pub name: String,
+    /// File name suffixes without the leading dot, such as `tar.gz` and `tgz`.
+    /// They are also accepted as aliases of the name.
+    pub extensions: Vec<String>,
+    /// Signatures given as byte offset and expected bytes, within the first [`SNIFF_LEN`] bytes.
+    pub magic: Vec<(usize, Vec<u8>)>,
+    pub reader: Option<Arc<dyn ArchiveReader>>,
+    pub writer: Option<Arc<dyn ArchiveWriter>>,
+}
+
+impl ArchiveFormat {
+    pub fn new(name: &str) -> Self {
+        ArchiveFormat {
+            name: name.to_string(),
+            extensions: Vec::new(),
+            magic: Vec::new(),
+            reader: None,
+            writer: None,
+        }
+    }
+
+    pub fn with_extensions(mut self, extensions: &[&str]) -> Self {
+        self.extensions
+            .extend(extensions.iter().map(|ext| ext.to_string()));
+        self
+    }
+
+    pub fn with_magic(mut self, offset: usize, magic: &[u8]) -> Self {
+        self.magic.push((offset, magic.to_vec()));
+        self
+    }
+
+    pub fn with_reader(mut self, reader: impl ArchiveReader + 'static) -> Self {
+        self.reader = Some(Arc::new(reader));
+        self
+    }
+
+    pub fn with_writer(mut self, writer: impl ArchiveWriter + 'static) -> Self {
+        self.writer = Some(Arc::new(writer));
+        self
+    }
+
+    fn matches_header(&self, header: &[u8]) -> bool {
+        self.magic.iter().any(|(offset, magic)| {
+            header
+                .get(*offset..offset + magic.len())
+                .is_some_and(|bytes| bytes == magic.as_slice())
+        })
+    }
+}
+
+/// Maps format names, extensions and signatures to their implementations.
+///
+/// Formats registered later take precedence, so a registered format can override a built-in one.
+#[derive(Clone, Default)]
+pub struct FormatRegistry {
+    formats: Vec<ArchiveFormat>,
+}
+
+impl FormatRegistry {
+    /// Creates a registry holding only the built-in formats.
+    pub fn builtin() -> Self {
+        let mut registry = FormatRegistry::default();
+        registry.register(
+            ArchiveFormat::new("zip")
+                .with_extensions(&["zip"])
+                .with_magic(0, b"PK\x03\x04")
+                .with_magic(0, b"PK\x05\x06")
+                .with_magic(0, b"PK\x07\x08")
+                .with_reader(ZipFormat)
+                .with_writer(ZipFormat),
+        );
+        registry.register(
+            ArchiveFormat::new("7z")
+                .with_extensions(&["7z"])
+                .with_magic(0, b"7z\xBC\xAF\x27\x1C")
+                .with_reader(SevenZFormat)
+                .with_writer(SevenZFormat),
+        );
+        let tar = |name: &str, extensions: &[&str], codec| {
+            ArchiveFormat::new(name)
+                .with_extensions(extensions)
+                .with_reader(TarFormat(codec))
+                .with_writer(TarFormat(codec))
+        };
+        registry.register(tar("tar", &["tar"], TarCodec::None).with_magic(257, b"ustar"));
+        registry.register(
+            tar("tar.gz", &["tar.gz", "tgz", "gz"], TarCodec::Gzip).with_magic(0, b"\x1F\x8B"),
+        );
+        registry.register(
+            tar("tar.xz", &["tar.xz", "txz", "xz"], TarCodec::Xz).with_magic(0, b"\xFD7zXZ\x00"),
+        );
+        registry.register(
+            tar(
+                "tar.bz2",
+                &["tar.bz2", "tbz2", "tbz", "bz2"],
+                TarCodec::Bzip2,
+            )
+            .with_magic(0, b"BZh"),
+        );
+        registry.register(
+            tar("tar.lz4", &["tar.lz4", "lz4"], TarCodec::Lz4).with_magic(0, b"\x04\x22\x4D\x18"),
+        );
+        registry.register(
+            tar("tar.zst", &["tar.zst", "tzst", "zst"], TarCodec::Zstd)
+                .with_magic(0, b"\x28\xB5\x2F\xFD"),
+        );
+        registry
+    }
+
+    /// Adds a format, replacing any format registered under the same name.
+    pub fn register(&mut self, format: ArchiveFormat) {
+        self.formats.retain(|known| known.name != format.name);
+        self.formats.push(format);
+    }
+
+    /// Looks up a format by its name or one of its extensions, ignoring case and a leading dot.
+    pub fn get(&self, name: &str) -> Option<&ArchiveFormat> {
+        let name = name.trim_start_matches('.').to_ascii_lowercase();
+        self.formats
+            .iter()
+            .rev()
+            .find(|format| format.name == name || format.extensions.contains(&name))
+    }
+
+    /// Looks up the format with the longest extension matching the end of `file_name`.
+    pub fn from_file_name(&self, file_name: &str) -> Option<&ArchiveFormat> {
+        let file_name = file_name.to_ascii_lowercase();
+        self.formats
+            .iter()
+            .rev()
+            .filter_map(|format| {
+                format
+                    .extensions
+                    .iter()
+                    .filter(|ext| file_name.ends_with(&format!(".{}", ext)))
+                    .map(|ext| ext.len())
+                    .max()
+                    .map(|len| (len, format))
+            })
+            .fold(
+                None,
+                |best: Option<(usize, &ArchiveFormat)>, candidate| match best {
+                    Some(best) if best.0 >= candidate.0 => Some(best),
+                    _ => Some(candidate),
+                },
+            )
+            .map(|(_, format)| format)
+    }
+
+    /// Recognises a format from the leading bytes of a file.
+    pub fn sniff(&self, header: &[u8]) -> Option<&ArchiveFormat> {
+        self.formats
+            .iter()
+            .rev()
+            .find(|format| format.matches_header(header))
+    }
+}
+
+static REGISTRY: LazyLock<RwLock<FormatRegistry>> =
+    LazyLock::new(|| RwLock::new(FormatRegistry::builtin()));
+
+/// Registers a format for every archive operation of this process.
+pub fn register_format(format: ArchiveFormat) {
+    REGISTRY
+        .write()
+        .unwrap_or_else(|e| e.into_inner())
+        .register(format);
+}
+
+/// The process-wide registry, holding the built-in formats and any registered ones.
+pub fn registry() -> RwLockReadGuard<'static, FormatRegistry> {
+    REGISTRY.read().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Rejects a compression method for formats other than zip.
+pub fn reject_method(options: &CompressOptions) -> Result<(), DecompressError> {
+    match &options.method {
+        Some(method) => Err(DecompressError::InvalidOption(format!(
+            "compression method {} only applies to zip patches",
+            method
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Rejects long-distance matching for outputs that are not compressed with zstd.
+pub fn reject_long_distance(options: &CompressOptions) -> Result<(), DecompressError> {
+    if options.long_distance {
+        return Err(DecompressError::InvalidOption(
+            "long-distance matching only applies to zstd-compressed tar patches".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Checks a requested compression level against the range of an encoder, or returns its default.
+pub fn check_level(
+    name: &str,
+    range: RangeInclusive<i32>,
+    default: i32,
+    level: Option<i32>,
+) -> Result<i32, DecompressError> {
+    let level = level.unwrap_or(default);
+    if !range.contains(&level) {
+        return Err(DecompressError::InvalidOption(format!(
+            "{} level must be within {:?}, got {}",
+            name, range, level
+        )));
+    }
+    Ok(level)
+}
+
+/// Rejects a compression level for outputs that are not compressed.
+fn reject_level(name: &str, level: Option<i32>) -> Result<(), DecompressError> {
+    match level {
+        Some(_) => Err(DecompressError::InvalidOption(format!(
+            "{} patches are not compressed and take no level",
+            name
+        ))),
+        None => Ok(()),
+    }
+}
+
+struct ZipFormat;
+
+impl ZipFormat {
+    /// Parses the compression method and resolves the level, or `None` for stored entries.
+    fn settings(
+        options: &CompressOptions,
+    ) -> Result<(zip::CompressionMethod, Option<i32>), DecompressError> {
+        let method = match options.method.as_deref().unwrap_or("deflate") {
+            "stored" => zip::CompressionMethod::Stored,
+            "deflate" => zip::CompressionMethod::Deflated,
+            "bzip2" => zip::CompressionMethod::Bzip2,
+            "zstd" => zip::CompressionMethod::Zstd,
+            other => {
+                return Err(DecompressError::InvalidOption(format!(
+                    "unknown zip compression method {}, expected stored, deflate, bzip2 or zstd",
+                    other
+                )));
+            }
+        };
+        let level = match method {
+            zip::CompressionMethod::Stored => {
+                reject_level("stored zip", options.level)?;
+                return Ok((method, None));
+            }
+            zip::CompressionMethod::Bzip2 => check_level("bzip2", 1..=9, 6, options.level)?,
+            zip::CompressionMethod::Zstd => check_level(
+                "zstd",
+                zstd::compression_level_range(),
+                zstd::DEFAULT_COMPRESSION_LEVEL,
+                options.level,
+            )?,
+            _ => check_level("deflate", 0..=9, 6, options.level)?,
+        };
+        Ok((method, Some(level)))
+    }
+}
+
+fn is_zip_symlink(entry: &zip::read::ZipFile) -> bool {
+    entry
+        .unix_mode()
+        .is_some_and(|mode| mode & 0o170000 == 0o120000)
+}
+
+impl ArchiveReader for ZipFormat {
+    fn read(
+        &self,
+        input_path: &str,
+        tracker: &mut LimitTracker,
+        visit: &mut EntryVisitor,
+    ) -> Result<(), DecompressError> {
+        let file = File::open(input_path)?;
+        let mut archive = zip::read::ZipArchive::new(BufReader::new(file))?;
+        for i in 0..archive.len() {
+            let mut entry = archive.by_index(i)?;
+            let Some(name) = tracker.entry_name(Path::new(entry.name()))? else {
+                continue;
+            };
+            tracker.check_declared(&name, entry.size(), Some(entry.compressed_size()))?;
+            let kind = if entry.is_dir() {
+                EntryKind::Dir
+            } else if is_zip_symlink(&entry) {
+                let mut target = String::new();
+                tracker
+                    .reader(&name, &mut entry)
+                    .read_to_string(&mut target)?;
+                EntryKind::Symlink(target)
+            } else {
+                EntryKind::File
+            };
+            let archive_entry = ArchiveEntry {
+                name,
+                kind,
+                mode: entry.unix_mode(),
+                zip_meta: Some((entry.crc32(), entry.size())),
+            };
+            visit(
+                &archive_entry,
+                &mut tracker.reader(&archive_entry.name, &mut entry),
+            )?;
+        }
+        Ok(())
+    }
+
+    fn total_size(&self, input_path: &str) -> Option<u64> {
+        let file = File::open(input_path).ok()?;
+        let mut archive = zip::read::ZipArchive::new(BufReader::new(file)).ok()?;
+        let mut total = 0;
+        for i in 0..archive.len() {
+            total += archive.by_index_raw(i).ok()?.size();
+        }
+        Some(total)
+    }
+}
+
+impl ArchiveWriter for ZipFormat {
+    fn validate(&self, options: &CompressOptions) -> Result<(), DecompressError> {
+        // The zip writer cannot tune its zstd encoder, even with the zstd method.
+        reject_long_distance(options)?;
+        ZipFormat::settings(options).map(|_| ())
+    }
+
+    fn write(
+        &self,
+        input_path: &str,
+        output_path: &str,
+        options: &CompressOptions,
+    ) -> Result<(), DecompressError> {
+        let (method, level) = ZipFormat::settings(options)?;
+        let output_file = File::create(output_path)?;
+        let input_path_obj = Path::new(input_path);
+        let mut zip_writer = zip::write::ZipWriter::new(output_file);
+        let policy = StorePolicy {
+            method,
+            level,
+            extensions: &options.store_extensions,
+            min_saving: options.min_saving,
+        };
+        let options = FileOptions::default()
+            .compression_method(method)
+            .compression_level(level);
+        let progress = PhaseProgress::within(Phase::Compress);
+
+        if input_path_obj.is_dir() {
+            add_directory_to_zip(
+                &mut zip_writer,
+                input_path_obj,
+                input_path_obj,
+                options,
+                &policy,
+                &progress,
+            )?;
+        } else {
+            let name = input_path_obj.file_name().unwrap().to_string_lossy();
+            let metadata = std::fs::metadata(input_path_obj)?;
+            progress.entry(&name);
+            zip_writer.start_file(
+                name,
+                policy.file_options(options, input_path_obj, &metadata)?,
+            )?;
+            let mut f = File::open(input_path_obj)?;
+            std::io::copy(&mut progress.reader(&mut f), &mut zip_writer)?;
+        }
+        zip_writer.finish()?;
+        Ok(())
+    }
+}
+
+/// Carries the real permission bits of a file into its zip entry options.
+fn entry_options(options: FileOptions, metadata: &std::fs::Metadata) -> FileOptions {
+    match file_mode(metadata) {
+        Some(mode) => options.unix_permissions(mode),
+        None => options,
+    }
+}
+
+/// Discards written bytes, keeping only their count.
+struct ByteCounter(u64);
+
+impl Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Decides for each zip entry whether compressing it is worthwhile.
+struct StorePolicy<'a> {
+    method: zip::CompressionMethod,
+    level: Option<i32>,
+    extensions: &'a [String],
+    min_saving: Option<u8>,
+}
+
+impl StorePolicy<'_> {
+    fn should_store(&self, path: &Path, size: u64) -> Result<bool, std::io::Error> {
+        if self.method == zip::CompressionMethod::Stored {
+            return Ok(false);
+        }
+        let file_name = path.to_string_lossy().to_ascii_lowercase();
+        let listed = self.extensions.iter().any(|ext| {
+            let ext = ext.trim_start_matches('.').to_ascii_lowercase();
+            file_name.ends_with(&format!(".{}", ext))
+        });
+        if listed {
+            return Ok(true);
+        }
+        match self.min_saving {
+            Some(min_saving) if size > 0 => {
+                let compressed = self.trial_size(path)?;
+                let saved = size.saturating_sub(compressed);
+                Ok(saved * 100 < size * u64::from(min_saving))
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Compresses a file with the configured method and returns the compressed size.
+    fn trial_size(&self, path: &Path) -> Result<u64, std::io::Error> {
+        let mut input = File::open(path)?;
+        let mut counter = ByteCounter(0);
+        match self.method {
+            zip::CompressionMethod::Bzip2 => {
+                let level = bzip2::Compression::new(self.level.unwrap_or(6) as u32);
+                let mut encoder = bzip2::write::BzEncoder::new(&mut counter, level);
+                std::io::copy(&mut input, &mut encoder)?;
+                encoder.finish()?;
+            }
+            zip::CompressionMethod::Zstd => {
+                let level = self.level.unwrap_or(zstd::DEFAULT_COMPRESSION_LEVEL);
+                let mut encoder = zstd::stream::write::Encoder::new(&mut counter, level)?;
+                std::io::copy(&mut input, &mut encoder)?;
+                encoder.finish()?;
+            }
+            _ => {
+                let level = flate2::Compression::new(self.level.unwrap_or(6) as u32);
+                let mut encoder = flate2::write::DeflateEncoder::new(&mut counter, level);
+                std::io::copy(&mut input, &mut encoder)?;
+                encoder.finish()?;
+            }
+        }
+        Ok(counter.0)
+    }
+
+    /// Options for a file entry, switching to stored when compression does not pay off.
+    fn file_options(
+        &self,
+        options: FileOptions,
+        path: &Path,
+        metadata: &std::fs::Metadata,
+    ) -> Result<FileOptions, std::io::Error> {
+        let options = entry_options(options, metadata);
+        if self.should_store(path, metadata.len())? {
+            Ok(options
+                .compression_method(zip::CompressionMethod::Stored)
+                .compression_level(None))
+        } else {
+            Ok(options)
+        }
+    }
+}
+
+fn add_directory_to_zip<W: Write + Seek>(
+    zip_writer: &mut zip::write::ZipWriter<W>,
+    dir: &Path,
+    base: &Path,
+    options: FileOptions,
+    policy: &StorePolicy,
+    progress: &PhaseProgress,
+) -> Result<(), std::io::Error> {
+    let entries = std::fs::read_dir(dir)?;
+    for entry in entries {
+        let entry = entry?;
+        let path = entry.path();
+        let name = path
+            .strip_prefix(base)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?
+            .to_string_lossy()
+            .replace("\\", "/");
+        let file_type = entry.file_type()?;
+
+        if file_type.is_symlink() {
+            // Store the link itself rather than the file it points to.
+            let target = std::fs::read_link(&path)?;
+            zip_writer.add_symlink(name, target.to_string_lossy().replace("\\", "/"), options)?;
+        } else if file_type.is_file() {
+            progress.entry(&name);
+            zip_writer.start_file(
+                name,
+                policy.file_options(options, &path, &entry.metadata()?)?,
+            )?;
+            let mut f = File::open(&path)?;
+            std::io::copy(&mut progress.reader(&mut f), zip_writer)?;
+        } else if file_type.is_dir() {
+            let dir_name = if name.ends_with('/') {
+                name
+            } else {
+                format!("{}/", name)
+            };
+            zip_writer.add_directory(dir_name, entry_options(options, &entry.metadata()?))?;
+            add_directory_to_zip(zip_writer, &path, base, options, policy, progress)?;
+        }
+    }
+    Ok(())
+}
+
+struct SevenZFormat;
+
+impl ArchiveReader for SevenZFormat {
+    fn read(
+        &self,
+        input_path: &str,
+        tracker: &mut LimitTracker,
+        visit: &mut EntryVisitor,
+    ) -> Result<(), DecompressError> {
+        let mut reader =
+            sevenz_rust::SevenZReader::open(input_path, sevenz_rust::Password::empty())?;
+        // The callback can only return 7z errors, so limit violations are carried out here.
+        let mut failure = None;
+        reader.for_each_entries(|entry, data| {
+            let result = tracker
+                .entry_name(Path::new(entry.name()))
+                .and_then(|name| {
+                    let Some(name) = name else {
+                        return Ok(());
+                    };
+                    let kind = if entry.is_directory() {
+                        EntryKind::Dir
+                    } else {
+                        tracker.check_declared(&name, entry.size(), None)?;
+                        EntryKind::File
+                    };
+                    let entry = ArchiveEntry {
+                        name,
+                        kind,
+                        mode: None,
+                        zip_meta: None,
+                    };
+                    let mut data = tracker.reader(&entry.name, data);
+                    visit(&entry, &mut data)?;
+                    // Solid blocks must be read through to reach the following entries.
+                    std::io::copy(&mut data, &mut std::io::sink())?;
+                    Ok(())
+                });
+            match result {
+                Ok(()) => Ok(true),
+                Err(e) => {
+                    failure = Some(e);
+                    Ok(false)
+                }
+            }
+        })?;
+        failure.map_or(Ok(()), Err)
+    }
+}
+
+impl ArchiveWriter for SevenZFormat {
+    fn validate(&self, options: &CompressOptions) -> Result<(), DecompressError> {
+        reject_method(options)?;
+        reject_long_distance(options)?;
+        check_level("7z", 0..=9, 6, options.level).map(|_| ())
+    }
+
+    fn write(
+        &self,
+        input_path: &str,
+        output_path: &str,
+        options: &CompressOptions,
+    ) -> Result<(), DecompressError> {
+        let level = check_level("7z", 0..=9, 6, options.level)?;
+        let mut writer = sevenz_rust::SevenZWriter::create(output_path)?;
+        writer.set_content_methods(vec![
+            sevenz_rust::lzma::LZMA2Options::with_preset(level as u32).into(),
+        ]);
+        // The encoder reads the files itself, so only the entries can be reported.
+        let progress = PhaseProgress::within(Phase::Compress);
+        writer.push_source_path(input_path, |path| {
+            if path.is_file() {
+                progress.entry(&path.to_string_lossy());
+            }
+            true
+        })?;
+        writer.finish()?;
+        Ok(())
+    }
+}
+
+/// Compression applied around a tar stream.
+#[derive(Debug, Clone, Copy)]
+enum TarCodec {
+    None,
+    Gzip,
+    Xz,
+    Bzip2,
+    Lz4,
+    Zstd,
+}
+
+struct TarFormat(TarCodec);
+
+impl TarFormat {
+    /// Resolves the compression level, or `None` for plain tar.
+    fn level(&self, level: Option<i32>) -> Result<Option<i32>, DecompressError> {
+        let level = match self.0 {
+            TarCodec::None => {
+                reject_level("tar", level)?;
+                return Ok(None);
+            }
+            TarCodec::Gzip => check_level("gzip", 0..=9, 6, level)?,
+            TarCodec::Xz => check_level("xz", 0..=9, 6, level)?,
+            TarCodec::Bzip2 => check_level("bzip2", 1..=9, 6, level)?,
+            TarCodec::Lz4 => check_level("lz4", 0..=16, 0, level)?,
+            TarCodec::Zstd => check_level(
+                "zstd",
+                zstd::compression_level_range(),
+                zstd::DEFAULT_COMPRESSION_LEVEL,
+                level,
+            )?,
+        };
+        Ok(Some(level))
+    }
+}
+
+/// Opens a zstd decoder that also accepts archives written in long-distance mode.
+fn zstd_decoder<R: Read>(
+    reader: BufReader<R>,
+) -> Result<zstd::stream::read::Decoder<'static, BufReader<R>>, std::io::Error> {
+    let mut decoder = zstd::stream::read::Decoder::with_buffer(reader)?;
+    decoder.window_log_max(31)?;
+    Ok(decoder)
+}
+
+impl ArchiveReader for TarFormat {
+    fn read(
+        &self,
+        input_path: &str,
+        tracker: &mut LimitTracker,
+        visit: &mut EntryVisitor,
+    ) -> Result<(), DecompressError> {
+        let reader = BufReader::new(File::open(input_path)?);
+        let stream: Box<dyn Read> = match self.0 {
+            TarCodec::None => Box::new(reader),
+            TarCodec::Gzip => Box::new(flate2::read::GzDecoder::new(reader)),
+            TarCodec::Xz => Box::new(xz2::read::XzDecoder::new(reader)),
+            TarCodec::Bzip2 => Box::new(bzip2::read::BzDecoder::new(reader)),
+            TarCodec::Lz4 => Box::new(lz4::Decoder::new(reader)?),
+            TarCodec::Zstd => Box::new(zstd_decoder(reader)?),
+        };
+        let mut archive = tar::Archive::new(stream);
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let Some(name) = tracker.entry_name(&entry.path()?)? else {
+                continue;
+            };
+            let entry_type = entry.header().entry_type();
+            let link_name = || -> Result<String, DecompressError> {
+                Ok(entry
+                    .link_name()?
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .to_string())
+            };
+            let kind = if entry_type.is_dir() {
+                EntryKind::Dir
+            } else if entry_type.is_symlink() {
+                EntryKind::Symlink(link_name()?)
+            } else if entry_type.is_hard_link() {
+                EntryKind::HardLink(link_name()?)
+            } else if entry_type.is_file() {
+                EntryKind::File
+            } else {
+                continue;
+            };
+            tracker.check_declared(&name, entry.size(), None)?;
+            let archive_entry = ArchiveEntry {
+                name,
+                kind,
+                mode: entry.header().mode().ok(),
+                zip_meta: None,
+            };
+            visit(
+                &archive_entry,
+                &mut tracker.reader(&archive_entry.name, &mut entry),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes the tar stream, reporting its size as the progress of the phase.
+fn create_tar_builder<W: Write>(writer: W, input_path: &str) -> Result<W, DecompressError> {
+    let progress = PhaseProgress::within(Phase::Compress);
+    let mut builder = tar::Builder::new(progress.writer(writer));
+    builder.follow_symlinks(false);
+    builder.append_dir_all(".", input_path)?;
+    builder.finish()?;
+    Ok(builder.into_inner()?.into_inner())
+}
+
+impl ArchiveWriter for TarFormat {
+    fn validate(&self, options: &CompressOptions) -> Result<(), DecompressError> {
+        reject_method(options)?;
+        if !matches!(self.0, TarCodec::Zstd) {
+            reject_long_distance(options)?;
+        }
+        self.level(options.level).map(|_| ())
+    }
+
+    fn write(
+        &self,
+        input_path: &str,
+        output_path: &str,
+        options: &CompressOptions,
+    ) -> Result<(), DecompressError> {
+        // Tar formats always resolve to a level except plain tar.
+        let level = self.level(options.level)?.unwrap_or_default();
+        let output_file = File::create(output_path)?;
+        match self.0 {
+            TarCodec::None => {
+                create_tar_builder(output_file, input_path)?;
+            }
+            TarCodec::Gzip => {
+                let encoder = flate2::write::GzEncoder::new(
+                    output_file,
+                    flate2::Compression::new(level as u32),
+                );
+                create_tar_builder(encoder, input_path)?.finish()?;
+            }
+            TarCodec::Xz => {
+                let encoder = xz2::write::XzEncoder::new(output_file, level as u32);
+                create_tar_builder(encoder, input_path)?.finish()?;
+            }
+            TarCodec::Bzip2 => {
+                let encoder = bzip2::write::BzEncoder::new(
+                    output_file,
+                    bzip2::Compression::new(level as u32),
+                );
+                create_tar_builder(encoder, input_path)?.finish()?;
+            }
+            TarCodec::Lz4 => {
+                let encoder = lz4::EncoderBuilder::new()
+                    .level(level as u32)
+                    .build(output_file)?;
+                let (_inner, result) = create_tar_builder(encoder, input_path)?.finish();
+                result.map_err(DecompressError::Io)?;
+            }
+            TarCodec::Zstd => {
+                let mut encoder = zstd::stream::write::Encoder::new(output_file, level)?;
+                if options.long_distance {
+                    encoder.long_distance_matching(true)?;
+                    encoder.window_log(27)?;
+                }
+                create_tar_builder(encoder, input_path)?.finish()?;
+            }
+        }
+        Ok(())
+    }
+}
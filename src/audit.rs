@@ -0,0 +1,74 @@
+use log::Level;
+
+use crate::filter::PathFilter;
+
+/// 文件决定日志的 target，便于与其他日志区分
+pub const TARGET: &str = "pulonia::decision";
+
+/// 比较时对一个文件或顶层目录作出的决定
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    /// 被过滤规则排除，不参与比较
+    Ignored,
+    Unchanged,
+    Added,
+    Modified,
+    Deleted,
+    /// 两个输入各自唯一、名称不同的顶层目录被对齐后比较
+    Aligned,
+    /// 顶层目录名称不像同一目录的两个版本，未自动对齐
+    Unaligned,
+}
+
+impl Decision {
+    pub fn name(self) -> &'static str {
+        match self {
+            Decision::Ignored => "ignored",
+            Decision::Unchanged => "unchanged",
+            Decision::Added => "added",
+            Decision::Modified => "modified",
+            Decision::Deleted => "deleted",
+            Decision::Aligned => "aligned",
+            Decision::Unaligned => "unaligned",
+        }
+    }
+
+    /// 进入补丁或迁移记录的决定为 info，其余为 debug；未对齐的顶层目录可能导致整体替换，为 warn
+    pub fn level(self) -> Level {
+        match self {
+            Decision::Ignored | Decision::Unchanged => Level::Debug,
+            Decision::Added | Decision::Modified | Decision::Deleted | Decision::Aligned => {
+                Level::Info
+            }
+            Decision::Unaligned => Level::Warn,
+        }
+    }
+}
+
+/// 是否有日志记录该类决定，用于跳过原因的计算
+pub fn enabled(decision: Decision) -> bool {
+    log::log_enabled!(target: TARGET, decision.level())
+}
+
+/// 记录对一个文件的决定及原因，`path`、`decision` 和 `reason` 作为结构化字段输出
+pub fn record(decision: Decision, path: &str, reason: &str) {
+    log::log!(
+        target: TARGET,
+        decision.level(),
+        path = path,
+        decision = decision.name(),
+        reason = reason;
+        "{} {}: {}",
+        decision.name(),
+        path,
+        reason
+    );
+}
+
+/// 记录被过滤规则排除的路径及决定排除的规则
+pub fn ignored(filter: &PathFilter, path: &str, is_dir: bool) {
+    if enabled(Decision::Ignored) {
+        let reason = filter.exclusion_reason(path, is_dir).unwrap_or_default();
+        record(Decision::Ignored, path, &reason);
+    }
+}
@@ -12,25 +12,96 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use clap::Parser;
+use std::collections::BTreeMap;
+
+use clap::parser::ValueSource;
+use clap::{ArgAction, ArgMatches, Args, FromArgMatches, Parser, Subcommand};
+use pulonia::config::{ENV_LIST_SEPARATOR, Setting};
+use serde_json::Value;
+
+use crate::logging::LogFormat;
 
 #[derive(Debug, Parser)]
 #[command(author, version, about, long_about = None)]
 pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    // 未给出子命令时按 `diff` 处理，兼容旧的调用方式
+    #[command(flatten)]
+    pub diff: DiffArgs,
+    #[arg(
+        long = "config",
+        required = false,
+        global = true,
+        value_name = "FILE",
+        help = "TOML, YAML or JSON configuration file with option values and path rules (default: pulonia.toml, pulonia.yaml, pulonia.yml or pulonia.json in the working directory, or $PULONIA_CONFIG)"
+    )]
+    pub config: Option<String>,
+    #[arg(
+        long = "profile",
+        required = false,
+        global = true,
+        value_name = "NAME",
+        help = "Apply the named profile of the configuration file (default: $PULONIA_PROFILE)"
+    )]
+    pub profile: Option<String>,
+    #[command(flatten)]
+    pub log: LogArgs,
+}
+
+// 进度条和日志的选项，对所有子命令生效，也可以在配置文件和环境变量中设置
+#[derive(Debug, Args)]
+pub struct LogArgs {
+    #[arg(
+        short = 'q',
+        long = "quiet",
+        global = true,
+        help = "Do not show progress bars, which are otherwise shown when stderr is a terminal"
+    )]
+    pub quiet: bool,
+    #[arg(
+        short = 'v',
+        long = "verbose",
+        action = ArgAction::Count,
+        global = true,
+        help = "Log each added, modified and deleted file with the reason; repeat (-vv) to also log unchanged and ignored files"
+    )]
+    pub verbose: u8,
+    #[arg(
+        long = "log-file",
+        global = true,
+        value_name = "FILE",
+        help = "Append log records to this file instead of writing them to stderr"
+    )]
+    pub log_file: Option<String>,
+    #[arg(
+        long = "log-format",
+        global = true,
+        value_enum,
+        default_value = "text",
+        value_name = "FORMAT",
+        help = "Format of log records: text, or json with one object per line"
+    )]
+    pub log_format: LogFormat,
+}
+
+// `diff` 的选项，也可以不带子命令直接给出
+#[derive(Debug, Args)]
+pub struct DiffArgs {
     #[arg(
         short = 'a',
         long = "after",
-        required = true,
+        required = false,
         help = "Path to the new version compressed file"
     )]
-    pub after_path: String,
+    pub after_path: Option<String>,
     #[arg(
         short = 'b',
         long = "before",
-        required = true,
+        required = false,
         help = "Path to the previous version compressed file"
     )]
-    pub before_path: String,
+    pub before_path: Option<String>,
     #[arg(
         short = 'o',
         long = "output",
@@ -48,7 +119,433 @@ pub struct Cli {
         short = 'f',
         long = "format",
         required = false,
-        help = "Patch file format (e.g., bsdiff, zstd)"
+        help = "Patch file format (e.g., zip, tar.gz, tar.zst)"
     )]
     pub format: Option<String>,
+    #[arg(
+        long = "archive-ignore",
+        required = false,
+        help = "Also apply the .puloniaignore file at the root of each input"
+    )]
+    pub archive_ignore: bool,
+    #[arg(
+        long = "stream",
+        required = false,
+        help = "Hash archive entries in memory instead of extracting both archives to disk"
+    )]
+    pub stream: bool,
+    #[arg(
+        long = "zip-prefilter",
+        required = false,
+        help = "When both inputs are zip files, compare CRC32 and size before hashing"
+    )]
+    pub zip_prefilter: bool,
+    #[arg(
+        long = "normalize",
+        required = false,
+        value_name = "GLOB[=OPTIONS]",
+        help = "Compare matching text files after normalising eol, trailing-whitespace and/or bom (repeatable)"
+    )]
+    pub normalize: Vec<String>,
+    #[arg(
+        long = "nested",
+        required = false,
+        value_name = "GLOB",
+        help = "Diff inner archives matching the glob (e.g. '*.jar') by their contents (repeatable)"
+    )]
+    pub nested: Vec<String>,
+    #[arg(
+        long = "level",
+        required = false,
+        allow_negative_numbers = true,
+        help = "Compression level of the patch, checked against the output format (e.g., 0-9 for zip, 1-22 for zstd)"
+    )]
+    pub level: Option<i32>,
+    #[arg(
+        long = "method",
+        required = false,
+        help = "Compression method for zip patches: stored, deflate, bzip2 or zstd"
+    )]
+    pub method: Option<String>,
+    #[arg(
+        long = "store-extensions",
+        required = false,
+        num_args = 0..,
+        value_delimiter = ',',
+        value_name = "EXT",
+        help = "Extensions stored without compression in zip patches; replaces the built-in list of already compressed formats, pass no value to disable"
+    )]
+    pub store_extensions: Option<Vec<String>>,
+    #[arg(
+        long = "min-saving",
+        required = false,
+        value_name = "PERCENT",
+        value_parser = clap::value_parser!(u8).range(0..=100),
+        help = "Store zip patch entries as is unless a trial compression shrinks them by at least this percentage"
+    )]
+    pub min_saving: Option<u8>,
+    #[arg(
+        long = "long",
+        required = false,
+        help = "Enable zstd long-distance matching for large patches"
+    )]
+    pub long_distance: bool,
+    #[arg(
+        long = "before-format",
+        required = false,
+        help = "Format of the previous version archive, overriding detection (e.g., zip, tar.gz)"
+    )]
+    pub before_format: Option<String>,
+    #[arg(
+        long = "after-format",
+        required = false,
+        help = "Format of the new version archive, overriding detection (e.g., zip, tar.gz)"
+    )]
+    pub after_format: Option<String>,
+    #[arg(
+        long = "before-root",
+        required = false,
+        value_name = "SUBPATH",
+        help = "Only compare this sub-directory of the previous version"
+    )]
+    pub before_root: Option<String>,
+    #[arg(
+        long = "after-root",
+        required = false,
+        value_name = "SUBPATH",
+        help = "Only compare and pack this sub-directory of the new version"
+    )]
+    pub after_root: Option<String>,
+    #[arg(
+        long = "strip-components",
+        required = false,
+        value_name = "N",
+        help = "Remove the first N directory levels of both inputs before comparing; by default differing single top-level folders are aligned automatically"
+    )]
+    pub strip_components: Option<usize>,
+    #[arg(
+        long = "max-total-size",
+        required = false,
+        value_parser = parse_size,
+        help = "Maximum bytes an input archive may expand to, with optional K/M/G/T suffix (default 32G)"
+    )]
+    pub max_total_size: Option<u64>,
+    #[arg(
+        long = "max-entries",
+        required = false,
+        help = "Maximum number of entries in an input archive (default 1000000)"
+    )]
+    pub max_entries: Option<u64>,
+    #[arg(
+        long = "max-entry-size",
+        required = false,
+        value_parser = parse_size,
+        help = "Maximum uncompressed size of a single entry, with optional K/M/G/T suffix (default 8G)"
+    )]
+    pub max_entry_size: Option<u64>,
+    #[arg(
+        long = "max-ratio",
+        required = false,
+        help = "Maximum ratio of uncompressed to compressed size of an input archive (default 1000)"
+    )]
+    pub max_ratio: Option<u64>,
+    #[arg(
+        long = "max-depth",
+        required = false,
+        help = "Maximum directory depth of an entry path (default 128)"
+    )]
+    pub max_depth: Option<usize>,
+    #[arg(
+        long = "max-nesting",
+        required = false,
+        help = "Maximum number of archives nested inside one another when expanding --nested archives (default 8)"
+    )]
+    pub max_nesting: Option<usize>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Compare two versions and write the patch and migration record (default)
+    Diff(Box<DiffArgs>),
+    /// Show which ignore rule decides whether a path is compared
+    CheckIgnore {
+        /// Paths relative to the input root; a trailing `/` marks a directory
+        #[arg(required = true)]
+        paths: Vec<String>,
+    },
+    /// List the files of a patch and check them against a migration record
+    Inspect {
+        /// Patch file to inspect
+        patch: String,
+        /// Migration record to summarise and compare with the patch
+        #[arg(long = "migration", value_name = "JSON")]
+        migration: Option<String>,
+        /// Format of the patch, overriding detection (e.g., zip, tar.gz)
+        #[arg(short = 'f', long = "format")]
+        format: Option<String>,
+    },
+    /// Inspect the configuration
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ConfigAction {
+    /// Print the resolved settings and where each value comes from
+    Show,
+}
+
+/// 只含 `diff` 选项的命令，用于按长选项名查找参数和重新解析
+fn diff_command() -> clap::Command {
+    DiffArgs::augment_args(clap::Command::new("pulonia").no_binary_name(true))
+}
+
+/// 只含日志选项的命令
+fn log_command() -> clap::Command {
+    LogArgs::augment_args(clap::Command::new("pulonia").no_binary_name(true))
+}
+
+/// 按长选项名查找 `diff` 的选项或日志选项
+fn find_arg(key: &str) -> Option<clap::Arg> {
+    [diff_command(), log_command()]
+        .into_iter()
+        .find_map(|command| {
+            command
+                .get_arguments()
+                .find(|arg| arg.get_long() == Some(key))
+                .cloned()
+        })
+}
+
+/// 判断 `key` 是否为 `diff` 的选项或日志选项的长选项名，可在配置文件和环境变量中设置
+pub fn is_option(key: &str) -> bool {
+    find_arg(key).is_some()
+}
+
+/// 收集命令行中显式给出的 `diff` 选项和日志选项，键为长选项名
+///
+/// 选项可以跟在 `diff` 子命令后，也可以不带子命令直接给出，两处都给出时以子命令后的为准。
+pub fn command_line_options(matches: &ArgMatches) -> BTreeMap<String, Setting> {
+    let mut options = given_options(matches);
+    if let Some(("diff", matches)) = matches.subcommand() {
+        options.extend(given_options(matches));
+    }
+    options
+}
+
+fn given_options(matches: &ArgMatches) -> BTreeMap<String, Setting> {
+    let mut options = BTreeMap::new();
+    let (diff, log) = (diff_command(), log_command());
+    for arg in diff.get_arguments().chain(log.get_arguments()) {
+        let (Some(long), id) = (arg.get_long(), arg.get_id().as_str()) else {
+            continue;
+        };
+        if matches.value_source(id) != Some(ValueSource::CommandLine) {
+            continue;
+        }
+        let value = if matches!(arg.get_action(), ArgAction::Count) {
+            Value::from(matches.get_count(id))
+        } else if !arg.get_action().takes_values() {
+            Value::Bool(true)
+        } else {
+            let values: Vec<Value> = matches
+                .get_raw(id)
+                .into_iter()
+                .flatten()
+                .map(|raw| Value::String(raw.to_string_lossy().into_owned()))
+                .collect();
+            match arg.get_action() {
+                ArgAction::Append => Value::Array(values),
+                _ => values.into_iter().last().unwrap_or(Value::Null),
+            }
+        };
+        options.insert(
+            long.to_string(),
+            Setting {
+                value,
+                source: "command line".to_string(),
+            },
+        );
+    }
+    options
+}
+
+/// 将合并后的选项还原为命令行参数重新解析，使配置文件和环境变量中的取值与
+/// 命令行参数经过相同的校验
+///
+/// 日志选项由 [`resolve_log`] 处理，其余未知的键报错。
+pub fn resolve(options: &BTreeMap<String, Setting>) -> Result<DiffArgs, String> {
+    let log = log_command();
+    let mut diff_options = Vec::new();
+    for (key, setting) in options {
+        if log
+            .get_arguments()
+            .all(|arg| arg.get_long() != Some(key.as_str()))
+        {
+            diff_options.push((key, setting));
+        }
+    }
+    parse_options(diff_command(), diff_options)
+}
+
+/// 解析合并后的日志选项，其余的键留给 [`resolve`]
+pub fn resolve_log(options: &BTreeMap<String, Setting>) -> Result<LogArgs, String> {
+    let log = log_command();
+    let log_options = options
+        .iter()
+        .filter(|(key, _)| {
+            log.get_arguments()
+                .any(|arg| arg.get_long() == Some(key.as_str()))
+        })
+        .collect();
+    parse_options(log, log_options)
+}
+
+fn parse_options<T: FromArgMatches>(
+    definition: clap::Command,
+    options: Vec<(&String, &Setting)>,
+) -> Result<T, String> {
+    let parse = |args: &[String]| {
+        definition
+            .clone()
+            .try_get_matches_from(args)
+            .and_then(|matches| T::from_arg_matches(&matches))
+    };
+    let mut args = Vec::new();
+    for (key, setting) in options {
+        let arg = definition
+            .get_arguments()
+            .find(|arg| arg.get_long() == Some(key.as_str()))
+            .ok_or_else(|| format!("unknown option '{}' ({})", key, setting.source))?;
+        let option_args = setting_args(key, arg, &setting.value)
+            .map_err(|err| format!("'{}' {} ({})", key, err, setting.source))?;
+        // 单独解析一次，以便在错误信息中指出取值来源
+        parse(&option_args)
+            .map_err(|err| format!("{} ({})", clap_message(&err), setting.source))?;
+        args.extend(option_args);
+    }
+    parse(&args).map_err(|err| clap_message(&err))
+}
+
+/// 按选项类型转换命令行和环境变量中的字符串取值，供 `config show` 输出
+pub fn typed_value(key: &str, value: &Value) -> Value {
+    let Value::String(text) = value else {
+        return value.clone();
+    };
+    let Some(arg) = find_arg(key) else {
+        return value.clone();
+    };
+    if matches!(arg.get_action(), ArgAction::Count) {
+        return text
+            .trim()
+            .parse::<u8>()
+            .map_or_else(|_| value.clone(), |count| Value::Number(count.into()));
+    }
+    if !arg.get_action().takes_values() {
+        return parse_bool(text).map_or_else(|_| value.clone(), Value::Bool);
+    }
+    text.parse::<i64>()
+        .map_or_else(|_| value.clone(), |number| Value::Number(number.into()))
+}
+
+fn setting_args(key: &str, arg: &clap::Arg, value: &Value) -> Result<Vec<String>, String> {
+    let flag = format!("--{}", key);
+    if matches!(arg.get_action(), ArgAction::Count) {
+        // 计数选项如 `verbose` 接受次数，`true` 相当于给出一次
+        let count = match value {
+            Value::Number(number) => number.as_u64().and_then(|n| u8::try_from(n).ok()),
+            Value::Bool(enabled) => Some(u8::from(*enabled)),
+            Value::String(text) => text
+                .trim()
+                .parse::<u8>()
+                .ok()
+                .or_else(|| parse_bool(text).ok().map(u8::from)),
+            _ => None,
+        }
+        .ok_or_else(|| "must be a count from 0 to 255 or true or false".to_string())?;
+        return Ok(vec![flag; usize::from(count)]);
+    }
+    if !arg.get_action().takes_values() {
+        let enabled = match value {
+            Value::Bool(enabled) => *enabled,
+            Value::String(text) => parse_bool(text)?,
+            _ => return Err("must be true or false".to_string()),
+        };
+        return Ok(if enabled { vec![flag] } else { Vec::new() });
+    }
+
+    let multiple = matches!(arg.get_action(), ArgAction::Append);
+    let items = match value {
+        Value::Array(items) if multiple => {
+            items.iter().map(scalar).collect::<Result<Vec<_>, _>>()?
+        }
+        // 列表选项的字符串取值按 `;` 拆分，与环境变量相同
+        Value::String(text) if multiple => text
+            .split(ENV_LIST_SEPARATOR)
+            .filter(|item| !item.is_empty())
+            .map(str::to_string)
+            .collect(),
+        Value::Array(_) => return Err("does not accept a list".to_string()),
+        value => vec![scalar(value)?],
+    };
+    if items.is_empty() {
+        // 允许不带值的列表选项以空列表覆盖默认值，如 `--store-extensions`
+        let accepts_empty = arg
+            .get_num_args()
+            .is_some_and(|range| range.min_values() == 0);
+        return Ok(if accepts_empty {
+            vec![flag]
+        } else {
+            Vec::new()
+        });
+    }
+    Ok(items
+        .into_iter()
+        .map(|item| format!("{}={}", flag, item))
+        .collect())
+}
+
+fn scalar(value: &Value) -> Result<String, String> {
+    match value {
+        Value::String(text) => Ok(text.clone()),
+        Value::Number(number) => Ok(number.to_string()),
+        Value::Bool(enabled) => Ok(enabled.to_string()),
+        _ => Err("must be a string, number or boolean".to_string()),
+    }
+}
+
+fn parse_bool(text: &str) -> Result<bool, String> {
+    match text.trim().to_ascii_lowercase().as_str() {
+        "true" | "1" | "yes" | "on" => Ok(true),
+        "false" | "0" | "no" | "off" | "" => Ok(false),
+        _ => Err(format!("must be true or false, not '{}'", text)),
+    }
+}
+
+/// clap 错误的第一行，去掉 `error: ` 前缀
+fn clap_message(err: &clap::Error) -> String {
+    let text = err.to_string();
+    let line = text.lines().next().unwrap_or_default();
+    line.strip_prefix("error: ").unwrap_or(line).to_string()
+}
+
+/// 解析带有 K/M/G/T 后缀（以 1024 为进制）的字节数
+fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let (number, shift) = match value.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&value[..i], 10),
+        Some((i, 'm' | 'M')) => (&value[..i], 20),
+        Some((i, 'g' | 'G')) => (&value[..i], 30),
+        Some((i, 't' | 'T')) => (&value[..i], 40),
+        _ => (value, 0),
+    };
+    let number: u64 = number
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid size '{}'", value))?;
+    number
+        .checked_mul(1u64 << shift)
+        .ok_or_else(|| format!("size '{}' is too large", value))
 }
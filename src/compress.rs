@@ -1,10 +1,19 @@
+use std::collections::{BTreeMap, HashSet};
 use std::fs::File;
-use std::io::{BufReader, Seek, Write};
-use std::path::Path;
+use std::io::{BufReader, Read};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
 use thiserror::Error;
-use zip::write::FileOptions;
 
-use crate::path::is_safe_path;
+use crate::archive::{ArchiveEntry, ArchiveWriter, EntryKind, EntryVisitor, SNIFF_LEN, registry};
+use crate::audit;
+use crate::diff::{FileEntries, FileEntry, HashOptions, hash_content, hash_nested};
+use crate::limits::{
+    ExtractLimits, LimitTracker, LimitUsage, Nesting, check_link_target, unwrap_limit_error,
+};
+use crate::path::is_safe_output_path;
+use crate::progress::{Phase, PhaseProgress, directory_size};
+use crate::text::find_rule;
 
 #[derive(Debug, Error)]
 pub enum DecompressError {
@@ -18,196 +27,784 @@ pub enum DecompressError {
     SevenZ(#[from] sevenz_rust::Error),
     #[error("Unsafe output path: {0}")]
     UnsafeOutputPath(String),
+    #[error("Invalid compression option: {0}")]
+    InvalidOption(String),
+    #[error("Archive has more than {limit} entries")]
+    TooManyEntries { limit: u64 },
+    #[error("Entry {name} is larger than {limit} bytes")]
+    EntryTooLarge { name: String, limit: u64 },
+    #[error("Archive expands to more than {limit} bytes")]
+    TotalSizeExceeded { limit: u64 },
+    #[error("Entry {name} exceeds the maximum compression ratio of {limit}")]
+    CompressionRatioExceeded { name: String, limit: u64 },
+    #[error("Entry {name} is nested deeper than {limit} directories")]
+    PathTooDeep { name: String, limit: usize },
+    #[error("Nested archive {name} is more than {limit} archives deep")]
+    NestingTooDeep { name: String, limit: usize },
+    #[error("Entry has an absolute or parent-relative path: {0}")]
+    UnsafeEntryPath(String),
+    #[error("Link {name} points outside the extraction root: {target}")]
+    LinkEscape { name: String, target: String },
+    #[error("Directory {0} not found in archive")]
+    SubtreeNotFound(String),
 }
 
-fn get_file_type(path: &Path) -> Option<String> {
-    let filename = path.file_name()?.to_string_lossy();
-    if filename.ends_with(".tar.gz") {
-        return Some("tar.gz".to_string());
+/// Settings that tune how the patch archive is written.
+#[derive(Debug, Clone, Default)]
+pub struct CompressOptions {
+    /// Compression level, or `None` for the format's default.
+    pub level: Option<i32>,
+    /// Compression method for zip patches: `stored`, `deflate`, `bzip2` or `zstd`.
+    pub method: Option<String>,
+    /// Enables zstd long-distance matching for large inputs.
+    pub long_distance: bool,
+    /// Extensions of already compressed files that zip patches store as is.
+    pub store_extensions: Vec<String>,
+    /// Percentage a zip entry must shrink by in a trial compression, or it is stored as is.
+    pub min_saving: Option<u8>,
+}
+
+/// Extensions of common already compressed formats, stored without compression in zip patches.
+pub const DEFAULT_STORE_EXTENSIONS: &[&str] = &[
+    "7z", "apk", "avif", "br", "bz2", "docx", "flac", "gif", "gz", "heic", "jar", "jpeg", "jpg",
+    "lz4", "m4a", "mkv", "mov", "mp3", "mp4", "ogg", "png", "pptx", "rar", "webm", "webp", "xlsx",
+    "xz", "zip", "zst",
+];
+
+/// Recognises an archive from its leading bytes only, ignoring the file name.
+pub fn sniff_file(path: &Path) -> Result<Option<String>, std::io::Error> {
+    let mut header = Vec::with_capacity(SNIFF_LEN);
+    File::open(path)?
+        .take(SNIFF_LEN as u64)
+        .read_to_end(&mut header)?;
+    Ok(registry().sniff(&header).map(|format| format.name.clone()))
+}
+
+/// Determines the format of an input archive.
+///
+/// Directories are reported as `dir`. An explicit `override_format` wins. Otherwise the leading bytes are sniffed,
+/// and the file name is only consulted when no known signature is found.
+pub fn detect_format(
+    input_path: &str,
+    override_format: Option<&str>,
+) -> Result<String, DecompressError> {
+    if Path::new(input_path).is_dir() {
+        return Ok("dir".to_string());
     }
-    if filename.ends_with(".tar.xz") {
-        return Some("tar.xz".to_string());
+
+    if let Some(format) = override_format {
+        return registry()
+            .get(format)
+            .map(|f| f.name.clone())
+            .ok_or_else(|| DecompressError::UnsupportedFormat(format.to_string()));
     }
-    if filename.ends_with(".tar.bz2") {
-        return Some("tar.bz2".to_string());
+
+    if let Some(format) = sniff_file(Path::new(input_path))? {
+        return Ok(format);
     }
-    if filename.ends_with(".tar.lz4") {
-        return Some("tar.lz4".to_string());
+
+    let path = Path::new(input_path);
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    if let Some(format) = registry().from_file_name(&file_name) {
+        return Ok(format.name.clone());
     }
-    if filename.ends_with(".7z") {
-        return Some("7z".to_string());
+    match path.extension() {
+        Some(ext) => Err(DecompressError::UnsupportedFormat(
+            ext.to_string_lossy().to_string(),
+        )),
+        None => Err(DecompressError::UnsupportedFormat(format!(
+            "Cannot detect the archive format of {}",
+            input_path
+        ))),
     }
-    path.extension()
-        .map(|ext| ext.to_string_lossy().to_string())
 }
 
-pub fn decompress(input_path: &str, output_path: &str) -> Result<(), DecompressError> {
-    let input_path_obj = Path::new(input_path);
+/// Normalises a user supplied sub-directory of an archive to a `/` separated relative name.
+pub fn normalize_subtree(subtree: &str) -> Result<String, DecompressError> {
+    normalize_entry_name(Path::new(subtree))
+        .ok_or_else(|| DecompressError::UnsafeEntryPath(subtree.to_string()))
+}
 
-    let file_type = get_file_type(input_path_obj)
-        .ok_or_else(|| DecompressError::UnsupportedFormat("No file extension found".to_string()))?;
+/// Returns the name of an entry relative to `subtree`, or `None` when it lies outside.
+fn scoped_name<'n>(name: &'n str, subtree: Option<&str>) -> Option<&'n str> {
+    match subtree {
+        Some(subtree) => name.strip_prefix(subtree)?.strip_prefix('/'),
+        None => Some(name),
+    }
+}
 
-    match file_type.as_str() {
-        "7z" => {
-            sevenz_rust::decompress_file(input_path, output_path)?;
-            Ok(())
-        }
-        "zip" => {
-            let file = File::open(input_path)?;
-            let reader = BufReader::new(file);
-            let mut archive = zip::read::ZipArchive::new(reader)?;
-            archive.extract(output_path)?;
-            Ok(())
+/// Walks every entry of an archive while enforcing `limits`.
+///
+/// `visit` receives the data of file entries through a reader that counts against the
+/// limits, and may leave it unread. Unsafe entry paths are rejected before `visit` runs.
+/// With a `subtree`, only entries below it are visited, named relative to it.
+fn read_archive(
+    input_path: &str,
+    file_type: &str,
+    limits: &ExtractLimits,
+    subtree: Option<&str>,
+    progress: Option<&PhaseProgress>,
+    visit: &mut EntryVisitor,
+) -> Result<(), DecompressError> {
+    let usage = Arc::default();
+    read_archive_sharing(
+        input_path, file_type, limits, subtree, progress, &usage, visit,
+    )
+}
+
+/// [`read_archive`], counting entries and bytes against `usage`, which is shared
+/// with enclosing and nested archives.
+fn read_archive_sharing(
+    input_path: &str,
+    file_type: &str,
+    limits: &ExtractLimits,
+    subtree: Option<&str>,
+    progress: Option<&PhaseProgress>,
+    usage: &Arc<LimitUsage>,
+    visit: &mut EntryVisitor,
+) -> Result<(), DecompressError> {
+    let archive_size = std::fs::metadata(input_path)?.len();
+    let mut tracker = LimitTracker::new(limits, archive_size)
+        .sharing(usage.clone())
+        .with_progress(progress);
+    let mut found = false;
+    let mut scoped = |entry: &ArchiveEntry, data: &mut dyn Read| {
+        let Some(subtree) = subtree else {
+            return visit(entry, data);
+        };
+        if entry.name == subtree {
+            found = true;
         }
-        "tar" => {
-            let file = File::open(input_path)?;
-            let reader = BufReader::new(file);
-            let mut archive = tar::Archive::new(reader);
-            archive.unpack(output_path)?;
-            Ok(())
+        let Some(name) = scoped_name(&entry.name, Some(subtree)) else {
+            return Ok(());
+        };
+        found = true;
+        let kind = match &entry.kind {
+            EntryKind::HardLink(target) => {
+                let target = normalize_entry_name(Path::new(target))
+                    .and_then(|target| scoped_name(&target, Some(subtree)).map(str::to_string))
+                    .ok_or_else(|| DecompressError::LinkEscape {
+                        name: entry.name.clone(),
+                        target: target.clone(),
+                    })?;
+                EntryKind::HardLink(target)
+            }
+            kind => kind.clone(),
+        };
+        let entry = ArchiveEntry {
+            name: name.to_string(),
+            kind,
+            mode: entry.mode,
+            zip_meta: entry.zip_meta,
+        };
+        visit(&entry, data)
+    };
+    // Clone the reader out so the registry is not locked while the archive is read.
+    let reader = registry()
+        .get(file_type)
+        .and_then(|format| format.reader.clone())
+        .ok_or_else(|| DecompressError::UnsupportedFormat(file_type.to_string()))?;
+    reader
+        .read(input_path, &mut tracker, &mut scoped)
+        .map_err(unwrap_limit_error)?;
+    match subtree {
+        Some(subtree) if !found => Err(DecompressError::SubtreeNotFound(subtree.to_string())),
+        _ => Ok(()),
+    }
+}
+
+/// Starts a progress phase over an archive, expecting the size its format records.
+fn archive_progress(phase: Phase, input_path: &str, file_type: &str) -> PhaseProgress {
+    PhaseProgress::start(phase, input_path, || {
+        let reader = registry()
+            .get(file_type)
+            .and_then(|format| format.reader.clone())?;
+        reader.total_size(input_path)
+    })
+}
+
+pub fn decompress(
+    input_path: &str,
+    output_path: &str,
+    format: Option<&str>,
+    limits: &ExtractLimits,
+    subtree: Option<&str>,
+) -> Result<(), DecompressError> {
+    let file_type = detect_format(input_path, format)?;
+    std::fs::create_dir_all(output_path)?;
+    let output_path = Path::new(output_path).canonicalize()?;
+    let progress = archive_progress(Phase::Extract, input_path, &file_type);
+    read_archive(
+        input_path,
+        &file_type,
+        limits,
+        subtree,
+        Some(&progress),
+        &mut |entry, data| write_archive_entry(&output_path, entry, data),
+    )
+}
+
+/// Turns a file or symbolic link entry into a manifest leaf.
+///
+/// Entries selected as nested archives are spooled to a temporary file and expanded
+/// one level below `nesting`.
+fn manifest_entry(
+    entry: &ArchiveEntry,
+    data: &mut dyn Read,
+    options: &HashOptions,
+    nesting: &Nesting,
+) -> Result<Option<FileEntry>, std::io::Error> {
+    match &entry.kind {
+        EntryKind::File if is_nested(&entry.name, options) => {
+            let mut spool = tempfile::NamedTempFile::new()?;
+            std::io::copy(data, &mut spool)?;
+            let file = match hash_nested(spool.path(), &entry.name, options, nesting)
+                .map_err(std::io::Error::other)?
+            {
+                Some(nested) => nested,
+                None => hash_content(
+                    &mut BufReader::new(File::open(spool.path())?),
+                    &entry.name,
+                    options,
+                )?,
+            };
+            Ok(Some(file.with_mode(entry.mode)))
         }
-        "gz" | "tar.gz" => {
-            let file = File::open(input_path)?;
-            let reader = BufReader::new(file);
-            let decoder = flate2::read::GzDecoder::new(reader);
-            let mut archive = tar::Archive::new(decoder);
-            archive.unpack(output_path)?;
-            Ok(())
+        EntryKind::File => Ok(Some(
+            hash_content(data, &entry.name, options)?.with_mode(entry.mode),
+        )),
+        EntryKind::Symlink(target) => Ok(Some(FileEntry::symlink(target.clone()))),
+        EntryKind::Dir | EntryKind::HardLink(_) => Ok(None),
+    }
+}
+
+fn is_nested(name: &str, options: &HashOptions) -> bool {
+    options.nested.iter().any(|matcher| matcher.is_match(name))
+}
+
+/// Applies Unix permission bits to an extracted file. A no-op elsewhere.
+fn set_mode(path: &Path, mode: Option<u32>) -> Result<(), std::io::Error> {
+    #[cfg(unix)]
+    if let Some(mode) = mode {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode & 0o7777))?;
+    }
+    #[cfg(not(unix))]
+    let _ = (path, mode);
+    Ok(())
+}
+
+/// Creates a symbolic link at `link` pointing to `target`.
+pub fn create_symlink(target: &Path, link: &Path) -> Result<(), std::io::Error> {
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::symlink(target, link)
+    }
+    #[cfg(windows)]
+    {
+        std::os::windows::fs::symlink_file(target, link)
+    }
+}
+
+/// Normalises an archive entry path to a `/` separated relative name.
+///
+/// Returns `None` for entries that would escape the extraction root.
+fn normalize_entry_name(path: &Path) -> Option<String> {
+    let mut parts = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => parts.push(part.to_string_lossy().to_string()),
+            Component::CurDir => {}
+            _ => return None,
         }
-        "xz" | "tar.xz" => {
-            let file = File::open(input_path)?;
-            let reader = BufReader::new(file);
-            let decoder = xz2::read::XzDecoder::new(reader);
-            let mut archive = tar::Archive::new(decoder);
-            archive.unpack(output_path)?;
+    }
+    if parts.is_empty() {
+        None
+    } else {
+        Some(parts.join("/"))
+    }
+}
+
+/// Hashes every regular file of an archive straight from the compressed stream.
+///
+/// Nothing is written to disk. Symbolic links are recorded with their target, and
+/// hard links share the leaf of the entry they point to.
+pub fn hash_archive(
+    input_path: &str,
+    format: Option<&str>,
+    options: &HashOptions,
+    limits: &ExtractLimits,
+    subtree: Option<&str>,
+) -> Result<FileEntries, DecompressError> {
+    hash_archive_at(
+        input_path,
+        format,
+        options,
+        limits,
+        subtree,
+        &Nesting::default(),
+    )
+}
+
+/// [`hash_archive`] for an archive at `nesting`, such as one found inside another.
+pub fn hash_archive_at(
+    input_path: &str,
+    format: Option<&str>,
+    options: &HashOptions,
+    limits: &ExtractLimits,
+    subtree: Option<&str>,
+    nesting: &Nesting,
+) -> Result<FileEntries, DecompressError> {
+    let file_type = detect_format(input_path, format)?;
+
+    let mut files = BTreeMap::new();
+    let progress = archive_progress(Phase::Hash, input_path, &file_type);
+    read_archive_sharing(
+        input_path,
+        &file_type,
+        limits,
+        subtree,
+        Some(&progress),
+        &nesting.usage,
+        &mut |entry, data| {
+            if !options.filter.allows(&entry.name) {
+                if !matches!(entry.kind, EntryKind::Dir) {
+                    audit::ignored(&options.filter, &entry.name, false);
+                }
+                return Ok(());
+            }
+            let file = match &entry.kind {
+                EntryKind::HardLink(target) => normalize_entry_name(Path::new(target))
+                    .and_then(|target| files.get(&target).cloned()),
+                _ => manifest_entry(entry, data, options, nesting)?,
+            };
+            if let Some(file) = file {
+                files.insert(entry.name.clone(), file);
+            }
             Ok(())
-        }
-        "bz2" | "tar.bz2" => {
-            let file = File::open(input_path)?;
-            let reader = BufReader::new(file);
-            let decoder = bzip2::read::BzDecoder::new(reader);
-            let mut archive = tar::Archive::new(decoder);
-            archive.unpack(output_path)?;
+        },
+    )?;
+    Ok(files)
+}
+
+/// A file or symbolic link stored in an archive, as listed by [`list_archive`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct PayloadEntry {
+    pub name: String,
+    /// Uncompressed size in bytes, or the length of the target path for a symbolic link.
+    pub size: u64,
+    pub file: FileEntry,
+}
+
+/// Lists every file and symbolic link of an archive with its size and manifest hash.
+pub fn list_archive(
+    input_path: &str,
+    format: Option<&str>,
+    limits: &ExtractLimits,
+) -> Result<Vec<PayloadEntry>, DecompressError> {
+    let file_type = detect_format(input_path, format)?;
+    let options = HashOptions::default();
+
+    let mut entries: Vec<PayloadEntry> = Vec::new();
+    let progress = archive_progress(Phase::Hash, input_path, &file_type);
+    read_archive(
+        input_path,
+        &file_type,
+        limits,
+        None,
+        Some(&progress),
+        &mut |entry, data| {
+            let listed = match &entry.kind {
+                EntryKind::File => {
+                    let mut counter = CountingReader {
+                        inner: data,
+                        count: 0,
+                    };
+                    let file = hash_content(&mut counter, &entry.name, &options)?;
+                    Some((counter.count, file.with_mode(entry.mode)))
+                }
+                EntryKind::Symlink(target) => {
+                    Some((target.len() as u64, FileEntry::symlink(target.clone())))
+                }
+                EntryKind::HardLink(target) => {
+                    normalize_entry_name(Path::new(target)).and_then(|target| {
+                        entries
+                            .iter()
+                            .find(|listed| listed.name == target)
+                            .map(|listed| (listed.size, listed.file.clone()))
+                    })
+                }
+                EntryKind::Dir => None,
+            };
+            if let Some((size, file)) = listed {
+                entries.push(PayloadEntry {
+                    name: entry.name.clone(),
+                    size,
+                    file,
+                });
+            }
             Ok(())
+        },
+    )?;
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(entries)
+}
+
+/// Counts the bytes read through it.
+struct CountingReader<'a> {
+    inner: &'a mut dyn Read,
+    count: u64,
+}
+
+impl Read for CountingReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.count += read as u64;
+        Ok(read)
+    }
+}
+
+/// Reads the CRC32 and uncompressed size of every file entry from a zip central directory.
+pub fn zip_entry_metadata(
+    input_path: &str,
+    subtree: Option<&str>,
+) -> Result<BTreeMap<String, (u32, u64)>, DecompressError> {
+    let file = File::open(input_path)?;
+    let mut archive = zip::read::ZipArchive::new(BufReader::new(file))?;
+    let mut entries = BTreeMap::new();
+    for i in 0..archive.len() {
+        let entry = archive.by_index_raw(i)?;
+        if entry.is_dir() {
+            continue;
         }
-        "lz4" | "tar.lz4" => {
-            let file = File::open(input_path)?;
-            let reader = BufReader::new(file);
-            let decoder = lz4::Decoder::new(reader)?;
-            let mut archive = tar::Archive::new(decoder);
-            archive.unpack(output_path)?;
-            Ok(())
+        if let Some(name) = entry.enclosed_name().and_then(normalize_entry_name)
+            && let Some(name) = scoped_name(&name, subtree)
+        {
+            entries.insert(name.to_string(), (entry.crc32(), entry.size()));
         }
-        _ => Err(DecompressError::UnsupportedFormat(file_type)),
-    }
-}
-
-fn add_directory_to_zip<W: Write + Seek>(
-    zip_writer: &mut zip::write::ZipWriter<W>,
-    dir: &Path,
-    base: &Path,
-    options: FileOptions,
-) -> Result<(), std::io::Error> {
-    let entries = std::fs::read_dir(dir)?;
-    for entry in entries {
-        let entry = entry?;
-        let path = entry.path();
-        let name = path
-            .strip_prefix(base)
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?
-            .to_string_lossy()
-            .replace("\\", "/");
-
-        if path.is_file() {
-            zip_writer.start_file(name, options)?;
-            let mut f = File::open(&path)?;
-            std::io::copy(&mut f, zip_writer)?;
-        } else if path.is_dir() {
-            let dir_name = if name.ends_with('/') {
-                name
+    }
+    Ok(entries)
+}
+
+/// Hashes two zip archives, using their central directories as a pre-filter.
+///
+/// Regular files with the same CRC32 and size on both sides are taken as unchanged
+/// and are not decompressed at all: both sides record the same `crc32:` placeholder.
+/// Old entries whose CRC32 or size differ from the new entry of the same name are
+/// certainly changed, so they get a placeholder too. Only new entries that changed or
+/// were added are hashed, since the migration records their hash and they go into the
+/// patch. Old entries without a new entry of the same name are hashed as well: they
+/// may be compared under another name once top-level folders are aligned or stripped,
+/// and a placeholder would never equal the hash on the other side. Entries covered
+/// by a text rule or a nested-archive glob are always hashed on both sides.
+/// `subtrees` optionally scope the old and new archive to a sub-directory each.
+pub fn hash_zip_pair(
+    before_path: &str,
+    after_path: &str,
+    options: &HashOptions,
+    limits: &ExtractLimits,
+    subtrees: (Option<&str>, Option<&str>),
+) -> Result<(FileEntries, FileEntries), DecompressError> {
+    let (before_subtree, after_subtree) = subtrees;
+    let before_meta = zip_entry_metadata(before_path, before_subtree)?;
+    let after_meta = zip_entry_metadata(after_path, after_subtree)?;
+    // Text rules may equate entries that differ only in line endings, and nested
+    // archives entries that differ only in their packaging.
+    let prefilter = |entry: &ArchiveEntry| {
+        matches!(entry.kind, EntryKind::File)
+            && entry.zip_meta.is_some()
+            && find_rule(&options.text_rules, &entry.name).is_none()
+            && !is_nested(&entry.name, options)
+    };
+    let placeholder = |entry: &ArchiveEntry| {
+        let (crc32, size) = entry.zip_meta.unwrap_or_default();
+        FileEntry::file(format!("crc32:{:08x}:{}", crc32, size)).with_mode(entry.mode)
+    };
+
+    let mut before_files = BTreeMap::new();
+    let nesting = Nesting::default();
+    let progress = archive_progress(Phase::Hash, before_path, "zip");
+    read_archive_sharing(
+        before_path,
+        "zip",
+        limits,
+        before_subtree,
+        Some(&progress),
+        &nesting.usage,
+        &mut |entry, data| {
+            if matches!(entry.kind, EntryKind::Dir) {
+                return Ok(());
+            }
+            if !options.filter.allows(&entry.name) {
+                audit::ignored(&options.filter, &entry.name, false);
+                return Ok(());
+            }
+            let file = if prefilter(entry) && after_meta.contains_key(&entry.name) {
+                Some(placeholder(entry))
             } else {
-                format!("{}/", name)
+                manifest_entry(entry, data, options, &nesting)?
             };
-            zip_writer.add_directory(dir_name, options)?;
-            add_directory_to_zip(zip_writer, &path, base, options)?;
+            if let Some(file) = file {
+                before_files.insert(entry.name.clone(), file);
+            }
+            Ok(())
+        },
+    )?;
+    drop(progress);
+
+    let mut after_files = BTreeMap::new();
+    let mut unchanged = 0;
+    let nesting = Nesting::default();
+    let progress = archive_progress(Phase::Hash, after_path, "zip");
+    read_archive_sharing(
+        after_path,
+        "zip",
+        limits,
+        after_subtree,
+        Some(&progress),
+        &nesting.usage,
+        &mut |entry, data| {
+            if matches!(entry.kind, EntryKind::Dir) {
+                return Ok(());
+            }
+            if !options.filter.allows(&entry.name) {
+                audit::ignored(&options.filter, &entry.name, false);
+                return Ok(());
+            }
+            let file =
+                if prefilter(entry) && before_meta.get(&entry.name) == entry.zip_meta.as_ref() {
+                    unchanged += 1;
+                    Some(placeholder(entry))
+                } else {
+                    manifest_entry(entry, data, options, &nesting)?
+                };
+            if let Some(file) = file {
+                after_files.insert(entry.name.clone(), file);
+            }
+            Ok(())
+        },
+    )?;
+
+    log::info!(
+        "Zip pre-filter: {} of {} entries matched by CRC32 and size, {} hashed",
+        unchanged,
+        after_files.len(),
+        after_files.len() - unchanged
+    );
+    Ok((before_files, after_files))
+}
+
+/// Resolves where an entry is written below the canonical `output_path`.
+///
+/// Existing parent directories must resolve inside `output_path`, so links extracted
+/// earlier cannot redirect writes elsewhere. A link already at the destination is
+/// removed rather than written through.
+fn entry_destination(output_path: &Path, name: &str) -> Result<PathBuf, DecompressError> {
+    let dest_path = output_path.join(name);
+    let mut current = output_path.to_path_buf();
+    for part in name.split('/').take(name.split('/').count() - 1) {
+        current.push(part);
+        match current.canonicalize() {
+            Ok(resolved) if !resolved.starts_with(output_path) => {
+                return Err(DecompressError::LinkEscape {
+                    name: name.to_string(),
+                    target: resolved.to_string_lossy().to_string(),
+                });
+            }
+            Ok(_) => {}
+            Err(_) => break,
         }
     }
-    Ok(())
+    if let Some(parent) = dest_path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    if std::fs::symlink_metadata(&dest_path).is_ok_and(|m| m.file_type().is_symlink()) {
+        std::fs::remove_file(&dest_path)?;
+    }
+    Ok(dest_path)
 }
 
-pub fn compress(input_path: &str, output_path: &str, format: &str) -> Result<(), DecompressError> {
-    // Check if output path is safe
-    if !is_safe_path(output_path) {
-        return Err(DecompressError::UnsafeOutputPath(
-            "Output path is not safe! Pulonia can only write files in the current directory or its subdirectories.".to_string()
-        ));
+/// Writes one entry below the canonical `output_path`, refusing links that leave it.
+fn write_archive_entry(
+    output_path: &Path,
+    entry: &ArchiveEntry,
+    data: &mut dyn Read,
+) -> Result<(), DecompressError> {
+    let dest_path = entry_destination(output_path, &entry.name)?;
+    match &entry.kind {
+        EntryKind::Dir => std::fs::create_dir_all(dest_path)?,
+        EntryKind::File => {
+            std::io::copy(data, &mut File::create(&dest_path)?)?;
+            set_mode(&dest_path, entry.mode)?;
+        }
+        EntryKind::Symlink(target) => {
+            check_link_target(&entry.name, target, false)?;
+            create_symlink(Path::new(target), &dest_path)?;
+        }
+        EntryKind::HardLink(target) => {
+            check_link_target(&entry.name, target, true)?;
+            let source = output_path.join(target).canonicalize()?;
+            if !source.starts_with(output_path) {
+                return Err(DecompressError::LinkEscape {
+                    name: entry.name.clone(),
+                    target: target.clone(),
+                });
+            }
+            std::fs::copy(source, &dest_path)?;
+        }
     }
+    Ok(())
+}
 
-    match format {
-        "7z" => {
-            sevenz_rust::compress_to_path(input_path, output_path)?;
-            Ok(())
-        }
-        "zip" => {
-            let output_file = File::create(output_path)?;
-            let input_path_obj = Path::new(input_path);
-            let mut zip_writer = zip::write::ZipWriter::new(output_file);
-            let options = FileOptions::default()
-                .compression_method(zip::CompressionMethod::Deflated)
-                .unix_permissions(0o755);
-
-            if input_path_obj.is_dir() {
-                add_directory_to_zip(&mut zip_writer, input_path_obj, input_path_obj, options)?;
-            } else {
-                let name = input_path_obj.file_name().unwrap().to_string_lossy();
-                zip_writer.start_file(name, options)?;
-                let mut f = File::open(input_path_obj)?;
-                std::io::copy(&mut f, &mut zip_writer)?;
+/// Reads one file entry of an archive into memory, or `None` when the archive lacks it.
+pub fn read_entry(
+    input_path: &str,
+    format: Option<&str>,
+    name: &str,
+    limits: &ExtractLimits,
+    subtree: Option<&str>,
+) -> Result<Option<Vec<u8>>, DecompressError> {
+    let file_type = detect_format(input_path, format)?;
+    let mut content = None;
+    read_archive(
+        input_path,
+        &file_type,
+        limits,
+        subtree,
+        None,
+        &mut |entry, data| {
+            if matches!(entry.kind, EntryKind::File) && entry.name == name {
+                let mut buffer = Vec::new();
+                data.read_to_end(&mut buffer)?;
+                content = Some(buffer);
             }
-            zip_writer.finish()?;
             Ok(())
-        }
-        // process tar and its compressed variants
-        _ => {
-            let output_file = File::create(output_path)?;
-
-            fn create_tar_builder<W: Write>(
-                writer: W,
-                input_path: &str,
-            ) -> Result<(), DecompressError> {
-                let mut builder = tar::Builder::new(writer);
-                builder.append_dir_all(".", input_path)?;
-                builder.finish()?;
-                Ok(())
-            }
-
-            match format {
-                "tar" => create_tar_builder(output_file, input_path),
-                "gz" | "tar.gz" => {
-                    let encoder =
-                        flate2::write::GzEncoder::new(output_file, flate2::Compression::default());
-                    create_tar_builder(encoder, input_path)
-                }
-                "xz" | "tar.xz" => {
-                    let encoder = xz2::write::XzEncoder::new(output_file, 6);
-                    create_tar_builder(encoder, input_path)
-                }
-                "bz2" | "tar.bz2" => {
-                    let encoder =
-                        bzip2::write::BzEncoder::new(output_file, bzip2::Compression::default());
-                    create_tar_builder(encoder, input_path)
-                }
-                "lz4" | "tar.lz4" => {
-                    let encoder = lz4::EncoderBuilder::new().build(output_file)?;
-                    let mut builder = tar::Builder::new(encoder);
-                    builder.append_dir_all(".", input_path)?;
-                    builder.finish()?;
-                    let (_inner, result) = builder.into_inner()?.finish();
-                    result.map_err(DecompressError::Io)?;
-                    Ok(())
+        },
+    )?;
+    Ok(content)
+}
+
+/// Extracts only the listed files of an archive into `output_path`.
+///
+/// Entry names are the relative paths produced by [`hash_archive`] for the same `subtree`.
+pub fn extract_entries(
+    input_path: &str,
+    format: Option<&str>,
+    output_path: &str,
+    entries: &[String],
+    limits: &ExtractLimits,
+    subtree: Option<&str>,
+) -> Result<(), DecompressError> {
+    let file_type = detect_format(input_path, format)?;
+    let wanted: HashSet<&str> = entries.iter().map(|e| e.as_str()).collect();
+    std::fs::create_dir_all(output_path)?;
+    let output_path = Path::new(output_path).canonicalize()?;
+
+    // Only some entries are read, so the size of the whole archive is no estimate.
+    let progress = PhaseProgress::start(Phase::Extract, input_path, || None);
+    // Hard links whose target is not extracted, keyed by the target.
+    let mut pending: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    read_archive(
+        input_path,
+        &file_type,
+        limits,
+        subtree,
+        Some(&progress),
+        &mut |entry, data| {
+            if matches!(entry.kind, EntryKind::Dir) || !wanted.contains(entry.name.as_str()) {
+                return Ok(());
+            }
+            if let EntryKind::HardLink(target) = &entry.kind {
+                check_link_target(&entry.name, target, true)?;
+                let target = normalize_entry_name(Path::new(target)).ok_or_else(|| {
+                    DecompressError::LinkEscape {
+                        name: entry.name.clone(),
+                        target: target.clone(),
+                    }
+                })?;
+                if !wanted.contains(target.as_str()) {
+                    pending.entry(target).or_default().push(entry.name.clone());
+                    return Ok(());
                 }
-                _ => Err(DecompressError::UnsupportedFormat(format.to_string())),
             }
-        }
+            write_archive_entry(&output_path, entry, data)
+        },
+    )?;
+    if pending.is_empty() {
+        return Ok(());
+    }
+
+    // A new or changed hard link may point at an unchanged file, which is not
+    // extracted: read the archive again and write the target's content to the links.
+    read_archive(
+        input_path,
+        &file_type,
+        limits,
+        subtree,
+        None,
+        &mut |entry, data| {
+            let Some(links) = pending.get(&entry.name) else {
+                return Ok(());
+            };
+            if !matches!(entry.kind, EntryKind::File) {
+                return Ok(());
+            }
+            let first = entry_destination(&output_path, &links[0])?;
+            std::io::copy(data, &mut File::create(&first)?)?;
+            set_mode(&first, entry.mode)?;
+            for link in &links[1..] {
+                std::fs::copy(&first, entry_destination(&output_path, link)?)?;
+            }
+            pending.remove(&entry.name);
+            Ok(())
+        },
+    )?;
+    match pending.into_iter().next() {
+        Some((target, links)) => Err(DecompressError::Io(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("hard link {} points to missing file {}", links[0], target),
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Checks that `format` can be written and accepts `options`, without writing anything.
+///
+/// Callers run this before the inputs are compared, so that a bad level, method or
+/// `long_distance` setting fails before any output exists.
+pub fn validate_options(format: &str, options: &CompressOptions) -> Result<(), DecompressError> {
+    checked_writer(format, options).map(|_| ())
+}
+
+fn checked_writer(
+    format: &str,
+    options: &CompressOptions,
+) -> Result<Arc<dyn ArchiveWriter>, DecompressError> {
+    let writer = registry()
+        .get(format)
+        .and_then(|format| format.writer.clone())
+        .ok_or_else(|| DecompressError::UnsupportedFormat(format.to_string()))?;
+    writer.validate(options)?;
+    Ok(writer)
+}
+
+pub fn compress(
+    input_path: &str,
+    output_path: &str,
+    format: &str,
+    options: &CompressOptions,
+) -> Result<(), DecompressError> {
+    // Check if output path is safe
+    if !is_safe_output_path(output_path) {
+        return Err(DecompressError::UnsafeOutputPath(
+            "Output path is not safe! Pulonia can only write files in the current directory or its subdirectories.".to_string()
+        ));
     }
+
+    // Validate options before creating the output file.
+    let writer = checked_writer(format, options)?;
+    let _progress = PhaseProgress::start(Phase::Compress, output_path, || {
+        Some(directory_size(Path::new(input_path)))
+    });
+    writer.write(input_path, output_path, options)
 }
@@ -0,0 +1,258 @@
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::filter::{PathFilter, PatternSet};
+use crate::hooks::{self, Hook};
+
+/// 未指定 `--config` 时依次在工作目录中查找的配置文件
+pub const DEFAULT_CONFIG_FILES: &[&str] = &[
+    "pulonia.toml",
+    "pulonia.yaml",
+    "pulonia.yml",
+    "pulonia.json",
+];
+
+/// 环境变量覆盖的前缀，如 `PULONIA_MAX_TOTAL_SIZE` 对应 `max-total-size`
+pub const ENV_PREFIX: &str = "PULONIA_";
+
+/// 指定配置文件的环境变量，优先级低于 `--config`
+pub const CONFIG_ENV: &str = "PULONIA_CONFIG";
+
+/// 指定配置档案的环境变量，优先级低于 `--profile`
+pub const PROFILE_ENV: &str = "PULONIA_PROFILE";
+
+/// 环境变量中列表各项的分隔符；逗号会出现在规范化规则和正则表达式中，因此不用逗号
+pub const ENV_LIST_SEPARATOR: char = ';';
+
+/// 配置文件内容，格式见 `docs/guide/configure.md`
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    /// 比较时排除的文件名、目录名或正则表达式
+    pub ignore: Vec<String>,
+    /// 非空时只比较匹配的路径
+    pub include: Vec<String>,
+    /// 用户数据等受保护路径（gitignore 语法），从不删除，仅在目标缺失时安装
+    pub preserve: Vec<String>,
+    /// 打包进补丁、安装前后运行的钩子
+    pub hooks: Vec<Hook>,
+    /// 命令行选项的取值，键为长选项名（如 `max-total-size`）
+    pub options: BTreeMap<String, Setting>,
+}
+
+/// 一个选项的取值及其来源，来源用于错误信息和 `config show`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Setting {
+    pub value: Value,
+    pub source: String,
+}
+
+impl Config {
+    /// 按扩展名解析 TOML、YAML 或 JSON 配置文件，并叠加 `profile` 指定的档案
+    pub fn load(path: &Path, profile: Option<&str>) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let extension = path.extension().and_then(|ext| ext.to_str()).unwrap_or("");
+        let value = match extension.to_ascii_lowercase().as_str() {
+            "toml" => toml::from_str(&text)
+                .map_err(|e| e.to_string())
+                .and_then(|table| toml_to_json(toml::Value::Table(table), "")),
+            // 空的 YAML 文件解析为 null，视为空表
+            "yaml" | "yml" => serde_yaml::from_str(&text)
+                .map(|value| match value {
+                    Value::Null => Value::Object(serde_json::Map::new()),
+                    value => value,
+                })
+                .map_err(|e| e.to_string()),
+            _ => serde_json::from_str(&text).map_err(|e| e.to_string()),
+        }
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+        let source = path.display().to_string();
+        Config::from_value(&value, &source, profile).map_err(|e| format!("{}: {}", source, e))
+    }
+
+    /// 由解析后的配置构建，`source` 为配置文件名
+    ///
+    /// `profile` 表中的同名档案覆盖顶层的取值。
+    pub fn from_value(value: &Value, source: &str, profile: Option<&str>) -> Result<Self, String> {
+        let object = value
+            .as_object()
+            .ok_or_else(|| "the configuration must be a table".to_string())?;
+
+        let mut config = Config::default();
+        for (key, value) in object {
+            if key != "profile" {
+                config.set(key, value, source)?;
+            }
+        }
+
+        let profiles = match object.get("profile") {
+            Some(Value::Object(profiles)) => Some(profiles),
+            None | Some(Value::Null) => None,
+            Some(_) => return Err("'profile' must be a table of profiles".to_string()),
+        };
+        if let Some(name) = profile {
+            let table = match profiles.and_then(|profiles| profiles.get(name)) {
+                Some(Value::Object(table)) => table,
+                Some(Value::Null) => &serde_json::Map::new(),
+                Some(_) => return Err(format!("profile '{}' must be a table", name)),
+                None => return Err(format!("profile '{}' is not defined", name)),
+            };
+            let source = format!("profile '{}' in {}", name, source);
+            for (key, value) in table {
+                config.set(key, value, &source)?;
+            }
+        }
+        Ok(config)
+    }
+
+    /// 应用 `PULONIA_*` 环境变量，优先级高于配置文件
+    ///
+    /// 列表用 `;` 分隔，`PULONIA_CONFIG` 和 `PULONIA_PROFILE` 由调用方处理。
+    /// 只有过滤规则和 `is_option` 认可的选项名会被应用，其余变量原样跳过并返回其名称，
+    /// 以免无关的环境变量使每条命令都失败。
+    pub fn apply_env<I>(
+        &mut self,
+        vars: I,
+        is_option: impl Fn(&str) -> bool,
+    ) -> Result<Vec<String>, String>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let mut skipped = Vec::new();
+        for (name, value) in vars {
+            let Some(key) = name.strip_prefix(ENV_PREFIX) else {
+                continue;
+            };
+            if name == CONFIG_ENV || name == PROFILE_ENV {
+                continue;
+            }
+            let key = key.to_ascii_lowercase();
+            let normalized = normalize_key(&key);
+            if !is_filter_key(&normalized) && !is_option(&normalized) {
+                skipped.push(name);
+                continue;
+            }
+            let value = if is_filter_key(&normalized) {
+                Value::Array(
+                    value
+                        .split(ENV_LIST_SEPARATOR)
+                        .filter(|item| !item.is_empty())
+                        .map(|item| Value::String(item.to_string()))
+                        .collect(),
+                )
+            } else {
+                Value::String(value)
+            };
+            self.set(&key, &value, &name)?;
+        }
+        Ok(skipped)
+    }
+
+    /// 设置一个键，`_` 与 `-` 视为相同；值为空时清除该键
+    pub fn set(&mut self, key: &str, value: &Value, source: &str) -> Result<(), String> {
+        let key = normalize_key(key);
+        match key.as_str() {
+            "ignore" => self.ignore = filter_list(&key, value)?,
+            "include" => self.include = filter_list(&key, value)?,
+            "preserve" => self.preserve = filter_list(&key, value)?,
+            "hooks" => self.hooks = hooks::parse_hooks(value)?,
+            "profile" | "config" => {
+                return Err(format!("'{}' cannot be set in {}", key, source));
+            }
+            _ if value.is_null() => {
+                self.options.remove(&key);
+            }
+            _ => {
+                self.options.insert(
+                    key,
+                    Setting {
+                        value: value.clone(),
+                        source: source.to_string(),
+                    },
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// 编译 `ignore` 和 `include` 规则
+    pub fn path_filter(&self) -> Result<PathFilter, String> {
+        PathFilter::new(&self.ignore, &self.include)
+    }
+
+    /// 编译 `preserve` 规则
+    pub fn preserve_set(&self) -> Result<PatternSet, String> {
+        PatternSet::from_rules("preserve", &self.preserve)
+    }
+}
+
+/// 在工作目录中查找默认配置文件
+pub fn discover() -> Option<PathBuf> {
+    DEFAULT_CONFIG_FILES
+        .iter()
+        .map(PathBuf::from)
+        .find(|path| path.is_file())
+}
+
+/// 将 TOML 取值转换为 JSON，选项没有日期类型，遇到日期时间时报错
+fn toml_to_json(value: toml::Value, key: &str) -> Result<Value, String> {
+    Ok(match value {
+        toml::Value::String(text) => Value::String(text),
+        toml::Value::Integer(number) => Value::from(number),
+        toml::Value::Float(number) => Value::from(number),
+        toml::Value::Boolean(flag) => Value::Bool(flag),
+        toml::Value::Datetime(date) => {
+            return Err(format!(
+                "'{}' is a date ({}); quote it to use it as a string",
+                key, date
+            ));
+        }
+        toml::Value::Array(items) => Value::Array(
+            items
+                .into_iter()
+                .map(|item| toml_to_json(item, key))
+                .collect::<Result<_, _>>()?,
+        ),
+        toml::Value::Table(table) => Value::Object(
+            table
+                .into_iter()
+                .map(|(name, item)| {
+                    let path = if key.is_empty() {
+                        name.clone()
+                    } else {
+                        format!("{}.{}", key, name)
+                    };
+                    toml_to_json(item, &path).map(|item| (name, item))
+                })
+                .collect::<Result<_, _>>()?,
+        ),
+    })
+}
+
+fn normalize_key(key: &str) -> String {
+    key.replace('_', "-")
+}
+
+fn is_filter_key(key: &str) -> bool {
+    matches!(key, "ignore" | "include" | "preserve")
+}
+
+fn filter_list(key: &str, value: &Value) -> Result<Vec<String>, String> {
+    if value.is_null() {
+        return Ok(Vec::new());
+    }
+    string_list(key, value)
+}
+
+fn string_list(key: &str, value: &Value) -> Result<Vec<String>, String> {
+    value
+        .as_array()
+        .and_then(|items| {
+            items
+                .iter()
+                .map(|item| item.as_str().map(str::to_string))
+                .collect()
+        })
+        .ok_or_else(|| format!("'{}' must be a list of strings", key))
+}
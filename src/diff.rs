@@ -1,101 +1,499 @@
+use globset::GlobMatcher;
+use regex::Regex;
 use serde_json::{Value, json};
 use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::io::{BufReader, Read};
-use std::path::PathBuf;
-use walkdir::WalkDir;
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+use thiserror::Error;
 
-pub fn get_hash(path: PathBuf) -> Value {
+use crate::audit;
+use crate::compress;
+use crate::filter::PathFilter;
+use crate::limits::{ExtractLimits, Nesting};
+use crate::progress::{Phase, PhaseProgress, directory_size};
+use crate::text::{TextRule, find_rule, hash_text};
+
+/// 生成清单时使用的选项
+#[derive(Debug, Clone, Default)]
+pub struct HashOptions {
+    /// 需要在比较前进行规范化的文本文件规则
+    pub text_rules: Vec<TextRule>,
+    /// 需要按内容展开比较的内层压缩包（如 `*.jar`）
+    pub nested: Vec<GlobMatcher>,
+    /// 读取内层压缩包时使用的上限
+    pub limits: ExtractLimits,
+    /// 配置文件中的 `ignore` / `include` 规则，被排除的路径不进入清单
+    pub filter: PathFilter,
+}
+
+/// 按目录展开的内层压缩包
+#[derive(Debug, Clone, PartialEq)]
+pub struct NestedArchive {
+    pub format: String,
+    pub files: FileEntries,
+}
+
+/// 清单中的单个叶子节点：普通文件或符号链接
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileEntry {
+    pub hash: String,
+    /// 符号链接指向的目标，普通文件为 `None`
+    pub link: Option<String>,
+    /// Unix 权限位，无法获取时（如 Windows 上打包的 zip）为 `None`
+    pub mode: Option<u32>,
+    /// 按文本规则规范化后的哈希，未匹配任何规则时为 `None`
+    pub text_hash: Option<String>,
+    /// 展开后的内层压缩包，此时 `hash` 由其内容计算，与压缩包本身的字节无关
+    pub nested: Option<Box<NestedArchive>>,
+}
+
+impl FileEntry {
+    pub fn file(hash: String) -> Self {
+        FileEntry {
+            hash,
+            link: None,
+            mode: None,
+            text_hash: None,
+            nested: None,
+        }
+    }
+
+    /// 符号链接的哈希取自其目标路径，目标变化即视为修改
+    pub fn symlink(target: String) -> Self {
+        FileEntry {
+            hash: get_symlink_hash(&target),
+            link: Some(target),
+            mode: None,
+            text_hash: None,
+            nested: None,
+        }
+    }
+
+    /// 内层压缩包的哈希与同内容目录的哈希计算方式相同
+    pub fn nested_archive(format: String, files: FileEntries) -> Self {
+        let (combined_hash, _) = tree_from_files(&files);
+        FileEntry {
+            nested: Some(Box::new(NestedArchive { format, files })),
+            ..FileEntry::file(digest_hex(&combined_hash))
+        }
+    }
+
+    pub fn with_mode(mut self, mode: Option<u32>) -> Self {
+        self.mode = mode.map(|m| m & 0o7777);
+        self
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut node = json!({ "hash": self.hash });
+        if let Some(link) = &self.link {
+            node["link"] = json!(link);
+        }
+        if let Some(mode) = self.mode {
+            node["mode"] = json!(format_mode(mode));
+        }
+        if let Some(text_hash) = &self.text_hash {
+            node["text_hash"] = json!(text_hash);
+        }
+        if let Some(nested) = &self.nested {
+            node["archive"] = json!(nested.format);
+            node["child"] = json!(tree_from_files(&nested.files).1);
+        }
+        node
+    }
+}
+
+/// 以八进制字符串表示权限位，如 `0755`
+pub fn format_mode(mode: u32) -> String {
+    format!("{:04o}", mode)
+}
+
+/// 读取文件的 Unix 权限位
+pub fn file_mode(metadata: &std::fs::Metadata) -> Option<u32> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        Some(metadata.permissions().mode() & 0o7777)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = metadata;
+        None
+    }
+}
+
+/// 相对路径 -> 叶子节点 的映射
+pub type FileEntries = BTreeMap<String, FileEntry>;
+
+/// 计算清单时的错误
+#[derive(Debug, Error)]
+pub enum DiffError {
+    #[error("Path does not exist: {0}")]
+    NotFound(String),
+    #[error("Path is neither a file nor a directory: {0}")]
+    UnsupportedFileType(String),
+    #[error("Failed to read {path}: {source}")]
+    Io {
+        path: String,
+        #[source]
+        source: std::io::Error,
+    },
+    /// 清单的目录结构不满足要求，如无法去掉指定层数的顶层目录
+    #[error("{0}")]
+    Layout(String),
+    /// 展开内层压缩包失败，如超出上限或层数过深
+    #[error("Failed to read nested archive {path}: {source}")]
+    Nested {
+        path: String,
+        #[source]
+        source: compress::DecompressError,
+    },
+}
+
+impl DiffError {
+    fn io(path: &Path) -> impl FnOnce(std::io::Error) -> DiffError + '_ {
+        move |source| DiffError::Io {
+            path: path.display().to_string(),
+            source,
+        }
+    }
+}
+
+pub fn get_hash(path: PathBuf, options: &HashOptions) -> Result<Value, DiffError> {
     if !path.exists() {
-        panic!("Path does not exist");
+        return Err(DiffError::NotFound(path.display().to_string()));
     }
+    let name = entry_name(&path);
+    let progress = PhaseProgress::start(Phase::Hash, &path.display().to_string(), || {
+        Some(directory_size(&path))
+    });
     if path.is_file() {
-        let filename = path.file_name().unwrap().to_string_lossy().to_string();
-        let hash = get_file_hash(path);
-        json!({
-            filename: {
+        progress.entry(&name);
+        let hash = get_file_hash(&path, &progress)?;
+        Ok(json!({
+            name: {
                 "hash": hash
             }
-        })
+        }))
     } else if path.is_dir() {
-        let dirname = path.file_name().unwrap().to_string_lossy().to_string();
-        let hash = get_directory_hash(&path);
-        let children = get_directory_children(&path);
-        json!({
-            dirname: {
-                "hash": hash,
+        let (combined_hash, children) = get_directory_children(&path, &path, options, &progress)?;
+        Ok(json!({
+            name: {
+                "hash": digest_hex(&combined_hash),
                 "child": children
             }
-        })
+        }))
     } else {
-        panic!("Path is neither a file nor a directory");
+        Err(DiffError::UnsupportedFileType(path.display().to_string()))
     }
 }
 
-fn get_file_hash(path: PathBuf) -> String {
-    let file = File::open(path).expect("Failed to open file for hashing");
+/// 清单中节点的名称，根目录等没有文件名的路径使用完整路径
+fn entry_name(path: &Path) -> String {
+    path.file_name()
+        .unwrap_or(path.as_os_str())
+        .to_string_lossy()
+        .to_string()
+}
+
+fn get_symlink_hash(target: &str) -> String {
+    format!("{:x}", Sha256::digest(target.as_bytes()))
+}
+
+fn read_symlink(path: &Path) -> Result<FileEntry, DiffError> {
+    let target = std::fs::read_link(path).map_err(DiffError::io(path))?;
+    Ok(FileEntry::symlink(
+        target.to_string_lossy().replace('\\', "/"),
+    ))
+}
+
+fn get_file_hash(path: &Path, progress: &PhaseProgress) -> Result<String, DiffError> {
+    let file = File::open(path).map_err(DiffError::io(path))?;
     let mut reader = BufReader::new(file);
+    hash_reader(&mut progress.reader(&mut reader)).map_err(DiffError::io(path))
+}
+
+/// 计算一个文件内容的清单节点，路径匹配文本规则时同时记录规范化后的哈希
+pub fn hash_content<R: Read + ?Sized>(
+    reader: &mut R,
+    path: &str,
+    options: &HashOptions,
+) -> std::io::Result<FileEntry> {
+    match find_rule(&options.text_rules, path) {
+        Some(rule) => {
+            let (hash, text_hash) = hash_text(reader, rule)?;
+            Ok(FileEntry {
+                text_hash: Some(text_hash),
+                ..FileEntry::file(hash)
+            })
+        }
+        None => Ok(FileEntry::file(hash_reader(reader)?)),
+    }
+}
+
+/// 计算任意字节流的 SHA-256，用于直接对压缩包中的条目做哈希
+pub fn hash_reader<R: Read + ?Sized>(reader: &mut R) -> std::io::Result<String> {
     let mut hasher = Sha256::new();
     let mut buffer = [0; 8192];
     loop {
-        let bytes_read = reader
-            .read(&mut buffer)
-            .expect("Failed to read file for hashing");
+        let bytes_read = reader.read(&mut buffer)?;
         if bytes_read == 0 {
             break;
         }
         hasher.update(&buffer[..bytes_read]);
     }
     let hash_result = hasher.finalize();
-    format!("{:x}", hash_result)
+    Ok(format!("{:x}", hash_result))
 }
 
-fn get_directory_hash(path: &PathBuf) -> String {
-    let mut combined_hash = String::new();
-    for entry in WalkDir::new(path) {
-        let entry = entry.expect("Failed to read directory entry");
-        if entry.path().is_file() {
-            combined_hash.push_str(&get_file_hash(entry.path().to_path_buf()));
-        }
+/// 路径匹配内层压缩包规则且文件确为压缩包时，按内容展开为清单节点
+///
+/// 不是压缩包的文件返回 `None`，由调用方按普通文件处理。`nesting` 为包含该文件的
+/// 压缩包所在的层级，内层压缩包与外层共用条目数和总大小的上限，层数超过
+/// `max_nesting` 时报错。
+pub fn hash_nested(
+    path: &Path,
+    name: &str,
+    options: &HashOptions,
+    nesting: &Nesting,
+) -> Result<Option<FileEntry>, compress::DecompressError> {
+    if !options.nested.iter().any(|matcher| matcher.is_match(name)) {
+        return Ok(None);
     }
-    format!("{:x}", sha2::Sha256::digest(combined_hash.as_bytes()))
+    let Some(format) = compress::sniff_file(path)? else {
+        return Ok(None);
+    };
+    let nesting = nesting.enter(name, &options.limits)?;
+    let files = compress::hash_archive_at(
+        &path.to_string_lossy(),
+        Some(&format),
+        options,
+        &options.limits,
+        None,
+        &nesting,
+    )?;
+    Ok(Some(FileEntry::nested_archive(format, files)))
 }
 
-fn get_directory_children(path: &PathBuf) -> Vec<Value> {
+/// 返回目录下所有文件哈希的拼接结果以及子节点列表
+///
+/// 拼接顺序与 `build_hash_tree` 相同，因此目录与压缩包得到的目录哈希一致。
+fn get_directory_children(
+    path: &Path,
+    root: &Path,
+    options: &HashOptions,
+    progress: &PhaseProgress,
+) -> Result<(String, Vec<Value>), DiffError> {
+    let mut combined_hash = String::new();
     let mut children = Vec::new();
 
-    for entry in std::fs::read_dir(path).expect("Failed to read directory") {
-        let entry = entry.expect("Failed to read directory entry");
+    // 按名称排序，使清单与条目顺序无关，并与 `build_hash_tree` 的结果一致
+    let mut entries = std::fs::read_dir(path)
+        .and_then(|entries| entries.collect::<std::io::Result<Vec<_>>>())
+        .map_err(DiffError::io(path))?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
         let entry_path = entry.path();
+        let file_type = entry.file_type().map_err(DiffError::io(&entry_path))?;
+        let relative_path = relative_name(&entry_path, root);
 
-        if entry_path.is_file() {
-            let filename = entry_path
-                .file_name()
-                .unwrap()
-                .to_string_lossy()
-                .to_string();
-            let hash = get_file_hash(entry_path);
+        // 被排除的目录整体跳过，文件还需满足 include 规则
+        let excluded = if file_type.is_dir() {
+            options.filter.ignores(&relative_path)
+        } else {
+            !options.filter.allows(&relative_path)
+        };
+        if excluded {
+            audit::ignored(&options.filter, &relative_path, file_type.is_dir());
+            continue;
+        }
+
+        let name = entry.file_name().to_string_lossy().to_string();
+        if file_type.is_symlink() {
+            let link = read_symlink(&entry_path)?;
+            combined_hash.push_str(&link.hash);
             children.push(json!({
-                filename: {
-                    "hash": hash
-                }
+                name: link.to_json()
             }));
-        } else if entry_path.is_dir() {
-            let dirname = entry_path
-                .file_name()
-                .unwrap()
-                .to_string_lossy()
-                .to_string();
-            let hash = get_directory_hash(&entry_path);
-            let sub_children = get_directory_children(&entry_path);
+        } else if file_type.is_file() {
+            progress.entry(&relative_path);
+            let mode = entry.metadata().ok().and_then(|m| file_mode(&m));
+            // 磁盘上的文件位于最外层，其中的内层压缩包各自从第一层开始计数
+            let nesting = Nesting::default();
+            let file_entry = match hash_nested(&entry_path, &relative_path, options, &nesting)
+                .map_err(|source| DiffError::Nested {
+                    path: entry_path.display().to_string(),
+                    source,
+                })? {
+                Some(nested) => nested,
+                None => File::open(&entry_path)
+                    .and_then(|file| {
+                        let mut reader = BufReader::new(file);
+                        hash_content(&mut progress.reader(&mut reader), &relative_path, options)
+                    })
+                    .map_err(DiffError::io(&entry_path))?,
+            }
+            .with_mode(mode);
+            combined_hash.push_str(&file_entry.hash);
+            children.push(json!({
+                name: file_entry.to_json()
+            }));
+        } else if file_type.is_dir() {
+            let (sub_combined, sub_children) =
+                get_directory_children(&entry_path, root, options, progress)?;
+            combined_hash.push_str(&sub_combined);
             children.push(json!({
-                dirname: {
-                    "hash": hash,
+                name: {
+                    "hash": digest_hex(&sub_combined),
                     "child": sub_children
                 }
             }));
         }
     }
 
-    children
+    Ok((combined_hash, children))
+}
+
+/// 计算相对于根目录、以 `/` 分隔的路径
+fn relative_name(path: &Path, root: &Path) -> String {
+    path.strip_prefix(root)
+        .unwrap_or(path)
+        .to_string_lossy()
+        .replace('\\', "/")
+}
+
+/// 若目录节点只包含一个子目录，返回该子目录的名称和节点
+pub fn single_root(node: &Value) -> Option<(&str, &Value)> {
+    let children = node.get("child")?.as_array()?;
+    let [child] = children.as_slice() else {
+        return None;
+    };
+    let (name, sub_node) = child.as_object()?.iter().next()?;
+    sub_node.get("child")?;
+    if sub_node.get("archive").is_some() {
+        return None;
+    }
+    Some((name.as_str(), sub_node))
+}
+
+/// 目录名中的版本号，如 `1`、`1.10.2`
+static VERSION: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\d+(?:\.\d+)*").expect("valid version pattern"));
+
+/// 判断两个顶层目录名是否只在版本号上不同，如 `app-1.0` 和 `app-1.1`、`build_v7` 和 `build_v8`
+///
+/// 将每段数字（含以点分隔的多段，如 `1.10.2`）替换为占位符后比较，两个名称都必须含有数字。
+pub fn is_version_pair(before_name: &str, after_name: &str) -> bool {
+    VERSION.is_match(before_name)
+        && VERSION.is_match(after_name)
+        && VERSION.replace_all(before_name, "#") == VERSION.replace_all(after_name, "#")
+}
+
+/// 去掉清单开头的 `count` 层目录，返回剩余的子树以及被去掉的路径前缀
+///
+/// 每一层都必须只有一个子目录，否则无法确定唯一的前缀。
+pub fn strip_components(node: &Value, count: usize) -> Result<(&Value, String), DiffError> {
+    let mut node = node;
+    let mut prefix: Vec<&str> = Vec::new();
+    for _ in 0..count {
+        match single_root(node) {
+            Some((name, sub_node)) => {
+                prefix.push(name);
+                node = sub_node;
+            }
+            None => {
+                let location = if prefix.is_empty() {
+                    "the top level".to_string()
+                } else {
+                    prefix.join("/")
+                };
+                return Err(DiffError::Layout(format!(
+                    "Cannot strip {} components: {} does not contain exactly one directory",
+                    count, location
+                )));
+            }
+        }
+    }
+    Ok((node, prefix.join("/")))
+}
+
+enum TreeNode {
+    File(FileEntry),
+    Dir(BTreeMap<String, TreeNode>),
+}
+
+/// 根据 路径 -> 哈希 的映射构建与 `get_hash` 相同结构的清单
+///
+/// 目录哈希由其下所有文件哈希按路径排序后拼接计算，因此结果与条目在压缩包中的顺序无关。
+pub fn build_hash_tree(root_name: &str, files: &FileEntries) -> Value {
+    let (combined_hash, children) = tree_from_files(files);
+    json!({
+        root_name: {
+            "hash": digest_hex(&combined_hash),
+            "child": children
+        }
+    })
+}
+
+fn tree_from_files(files: &FileEntries) -> (String, Vec<Value>) {
+    let mut root = BTreeMap::new();
+    for (path, file) in files {
+        let parts: Vec<&str> = path.split('/').filter(|p| !p.is_empty()).collect();
+        insert_tree_node(&mut root, &parts, file);
+    }
+    tree_dir_to_value(&root)
+}
+
+fn insert_tree_node(dir: &mut BTreeMap<String, TreeNode>, parts: &[&str], file: &FileEntry) {
+    match parts {
+        [] => {}
+        [name] => {
+            dir.insert(name.to_string(), TreeNode::File(file.clone()));
+        }
+        [name, rest @ ..] => {
+            let node = dir
+                .entry(name.to_string())
+                .or_insert_with(|| TreeNode::Dir(BTreeMap::new()));
+            if let TreeNode::File(_) = node {
+                *node = TreeNode::Dir(BTreeMap::new());
+            }
+            if let TreeNode::Dir(children) = node {
+                insert_tree_node(children, rest, file);
+            }
+        }
+    }
+}
+
+/// 返回目录下所有文件哈希的拼接结果以及子节点列表
+fn tree_dir_to_value(dir: &BTreeMap<String, TreeNode>) -> (String, Vec<Value>) {
+    let mut combined_hash = String::new();
+    let mut children = Vec::new();
+    for (name, node) in dir {
+        match node {
+            TreeNode::File(file) => {
+                combined_hash.push_str(&file.hash);
+                children.push(json!({
+                    name: file.to_json()
+                }));
+            }
+            TreeNode::Dir(sub_dir) => {
+                let (sub_combined, sub_children) = tree_dir_to_value(sub_dir);
+                combined_hash.push_str(&sub_combined);
+                children.push(json!({
+                    name: {
+                        "hash": digest_hex(&sub_combined),
+                        "child": sub_children
+                    }
+                }));
+            }
+        }
+    }
+    (combined_hash, children)
+}
+
+fn digest_hex(combined_hash: &str) -> String {
+    format!("{:x}", Sha256::digest(combined_hash.as_bytes()))
 }
@@ -0,0 +1,100 @@
+use thiserror::Error;
+
+use crate::compress::DecompressError;
+use crate::diff::DiffError;
+
+/// 生成了补丁或迁移记录，或命令执行成功
+pub const EXIT_OK: u8 = 0;
+/// 两个版本相同，没有生成任何文件
+pub const EXIT_IDENTICAL: u8 = 1;
+/// `inspect` 发现补丁与迁移记录不一致；与 `EXIT_IDENTICAL` 同为 1，表示否定的结果
+pub const EXIT_ISSUES_FOUND: u8 = 1;
+/// `check-ignore` 的路径都未被排除，与 `git check-ignore` 一致
+pub const EXIT_NOT_IGNORED: u8 = 1;
+/// 参数、配置、输入路径或输入格式有误
+pub const EXIT_INPUT_ERROR: u8 = 2;
+/// 读写文件失败
+pub const EXIT_IO_ERROR: u8 = 3;
+
+/// 命令行工具的顶层错误，`exit_code` 给出对应的退出码
+#[derive(Debug, Error)]
+pub enum PuloniaError {
+    /// 读取输入或写入补丁时压缩包出错
+    #[error("{context}: {source}")]
+    Decompress {
+        context: String,
+        #[source]
+        source: DecompressError,
+    },
+    /// 计算清单或对齐目录时出错
+    #[error("{context}: {source}")]
+    Diff {
+        context: String,
+        #[source]
+        source: DiffError,
+    },
+    /// 输入、输出或临时目录的路径不可用
+    #[error("Invalid {what} path: {reason}")]
+    Path { what: String, reason: String },
+    #[error("Invalid configuration: {0}")]
+    Config(String),
+    /// 其余无效输入，如规则、glob 或压缩包格式
+    #[error("{0}")]
+    Input(String),
+    #[error("{context}: {source}")]
+    Io {
+        context: String,
+        #[source]
+        source: std::io::Error,
+    },
+}
+
+impl PuloniaError {
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            PuloniaError::Decompress {
+                source: DecompressError::Io(_),
+                ..
+            }
+            | PuloniaError::Diff {
+                source: DiffError::Io { .. },
+                ..
+            }
+            | PuloniaError::Diff {
+                source:
+                    DiffError::Nested {
+                        source: DecompressError::Io(_),
+                        ..
+                    },
+                ..
+            }
+            | PuloniaError::Io { .. } => EXIT_IO_ERROR,
+            _ => EXIT_INPUT_ERROR,
+        }
+    }
+
+    /// 为压缩包错误附上说明，返回可直接用于 `map_err` 的闭包
+    pub fn decompress(context: impl Into<String>) -> impl FnOnce(DecompressError) -> Self {
+        let context = context.into();
+        move |source| PuloniaError::Decompress { context, source }
+    }
+
+    /// 为清单错误附上说明
+    pub fn diff(context: impl Into<String>) -> impl FnOnce(DiffError) -> Self {
+        let context = context.into();
+        move |source| PuloniaError::Diff { context, source }
+    }
+
+    /// 为读写错误附上说明
+    pub fn io(context: impl Into<String>) -> impl FnOnce(std::io::Error) -> Self {
+        let context = context.into();
+        move |source| PuloniaError::Io { context, source }
+    }
+
+    pub fn path(what: &str) -> impl FnOnce(String) -> Self + '_ {
+        move |reason| PuloniaError::Path {
+            what: what.to_string(),
+            reason,
+        }
+    }
+}
@@ -0,0 +1,265 @@
+use globset::{GlobBuilder, GlobMatcher};
+use regex::Regex;
+
+/// 未指定时在工作目录及输入根目录中查找的忽略文件
+pub const IGNORE_FILE: &str = ".puloniaignore";
+
+/// 配置文件中的一条路径规则
+///
+/// 不含正则元字符的规则按名称匹配：不含 `/` 时匹配任意一级文件名或目录名，
+/// 含 `/` 时匹配该相对路径及其下的所有文件；其余规则作为正则表达式在相对路径中查找。
+/// 这与 `preserve` 和忽略文件使用的 gitignore 语法（[`IgnorePattern`]）不同，
+/// 例如 `*.log` 在此处是无效的正则表达式，两者的对照见配置文档。
+#[derive(Debug, Clone)]
+enum PathRule {
+    Name(String),
+    Path(String),
+    Regex(Regex),
+}
+
+const REGEX_CHARS: &[char] = &[
+    '*', '+', '?', '(', ')', '[', ']', '{', '}', '|', '^', '$', '\\',
+];
+
+impl PathRule {
+    fn parse(rule: &str) -> Result<Self, String> {
+        if rule.contains(REGEX_CHARS) {
+            return Regex::new(rule)
+                .map(PathRule::Regex)
+                .map_err(|e| format!("Invalid regex '{}': {}", rule, e));
+        }
+        let rule = rule.trim_matches('/');
+        if rule.is_empty() {
+            return Err("Empty path rule".to_string());
+        }
+        if rule.contains('/') {
+            Ok(PathRule::Path(rule.to_string()))
+        } else {
+            Ok(PathRule::Name(rule.to_string()))
+        }
+    }
+
+    fn is_match(&self, path: &str) -> bool {
+        match self {
+            PathRule::Name(name) => path.split('/').any(|part| part == name),
+            PathRule::Path(prefix) => {
+                path == prefix
+                    || path
+                        .strip_prefix(prefix.as_str())
+                        .is_some_and(|rest| rest.starts_with('/'))
+            }
+            PathRule::Regex(regex) => regex.is_match(path),
+        }
+    }
+}
+
+/// 忽略文件中的一行，语义与 gitignore 相同
+///
+/// 支持 `**`、以 `/` 结尾只匹配目录、`!` 取反，以及含 `/` 时相对根目录锚定。
+#[derive(Debug, Clone)]
+struct IgnorePattern {
+    matcher: GlobMatcher,
+    negated: bool,
+    dir_only: bool,
+    source: String,
+    line: usize,
+    text: String,
+}
+
+impl IgnorePattern {
+    /// 解析一行规则，空行和注释返回 `None`
+    fn parse(line: &str, source: &str, number: usize) -> Result<Option<Self>, String> {
+        let text = line.trim_end_matches(['\r', '\n']);
+        // 行尾空格除非被转义，否则忽略
+        let mut pattern = text.trim_end_matches(' ');
+        if pattern.ends_with('\\') && text.len() > pattern.len() {
+            pattern = &text[..pattern.len() + 1];
+        }
+        if pattern.is_empty() || pattern.starts_with('#') {
+            return Ok(None);
+        }
+
+        let (negated, pattern) = match pattern.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, pattern.strip_prefix('\\').unwrap_or(pattern)),
+        };
+        let (dir_only, pattern) = match pattern.strip_suffix('/') {
+            Some(rest) => (true, rest),
+            None => (false, pattern),
+        };
+        // 开头或中间有 `/` 的规则相对根目录，否则匹配任意层级
+        let anchored = pattern.contains('/');
+        let pattern = pattern.strip_prefix('/').unwrap_or(pattern);
+        if pattern.is_empty() {
+            return Ok(None);
+        }
+        let glob = if anchored {
+            pattern.to_string()
+        } else {
+            format!("**/{}", pattern)
+        };
+        let matcher = GlobBuilder::new(&glob)
+            .literal_separator(true)
+            .build()
+            .map_err(|e| format!("{}:{}: invalid pattern '{}': {}", source, number, text, e))?
+            .compile_matcher();
+
+        Ok(Some(IgnorePattern {
+            matcher,
+            negated,
+            dir_only,
+            source: source.to_string(),
+            line: number,
+            text: text.to_string(),
+        }))
+    }
+}
+
+/// 一组 gitignore 语义的规则，最后一条匹配的规则生效
+#[derive(Debug, Clone, Default)]
+pub struct PatternSet {
+    patterns: Vec<IgnorePattern>,
+}
+
+impl PatternSet {
+    /// 由配置文件中的规则列表构建，`source` 用于说明规则来源
+    pub fn from_rules(source: &str, rules: &[String]) -> Result<Self, String> {
+        let mut set = PatternSet::default();
+        for (index, rule) in rules.iter().enumerate() {
+            if let Some(pattern) = IgnorePattern::parse(rule, source, index + 1)? {
+                set.patterns.push(pattern);
+            }
+        }
+        Ok(set)
+    }
+
+    /// 追加一个忽略文件中的规则，后追加的规则优先
+    pub fn add_file(&mut self, source: &str, text: &str) -> Result<(), String> {
+        for (index, line) in text.lines().enumerate() {
+            if let Some(pattern) = IgnorePattern::parse(line, source, index + 1)? {
+                self.patterns.push(pattern);
+            }
+        }
+        Ok(())
+    }
+
+    /// 路径是否被某条非取反规则选中
+    pub fn is_match(&self, path: &str, is_dir: bool) -> bool {
+        self.decide(path, is_dir)
+            .is_some_and(|pattern| !pattern.negated)
+    }
+
+    /// 与 gitignore 相同，父目录被选中时其下的路径无法再被取反规则排除
+    fn decide(&self, path: &str, is_dir: bool) -> Option<&IgnorePattern> {
+        let parts: Vec<&str> = path.split('/').collect();
+        for depth in 1..parts.len() {
+            if let Some(pattern) = self.last_pattern(&parts[..depth].join("/"), true)
+                && !pattern.negated
+            {
+                return Some(pattern);
+            }
+        }
+        self.last_pattern(path, is_dir)
+    }
+
+    fn last_pattern(&self, path: &str, is_dir: bool) -> Option<&IgnorePattern> {
+        self.patterns
+            .iter()
+            .rev()
+            .find(|pattern| (is_dir || !pattern.dir_only) && pattern.matcher.is_match(path))
+    }
+}
+
+/// 决定某个路径是否被排除的规则
+#[derive(Debug, Clone, PartialEq)]
+pub struct FilterMatch {
+    /// 规则所在的文件，如 `.puloniaignore` 或 `ignore`（配置文件）
+    pub source: String,
+    /// 规则在忽略文件中的行号
+    pub line: Option<usize>,
+    pub pattern: String,
+    /// 取反规则匹配时路径不被排除
+    pub negated: bool,
+}
+
+/// 构建清单时对相对路径的过滤
+///
+/// `include` 非空时只保留匹配的路径，随后再排除匹配 `ignore` 或忽略文件的路径。
+#[derive(Debug, Clone, Default)]
+pub struct PathFilter {
+    ignore: Vec<(String, PathRule)>,
+    include: Vec<PathRule>,
+    patterns: PatternSet,
+}
+
+impl PathFilter {
+    pub fn new(ignore: &[String], include: &[String]) -> Result<Self, String> {
+        Ok(PathFilter {
+            ignore: ignore
+                .iter()
+                .map(|rule| Ok((rule.clone(), PathRule::parse(rule)?)))
+                .collect::<Result<_, String>>()?,
+            include: include
+                .iter()
+                .map(|rule| PathRule::parse(rule))
+                .collect::<Result<_, String>>()?,
+            patterns: PatternSet::default(),
+        })
+    }
+
+    /// 追加一个忽略文件的规则，后追加的规则优先
+    pub fn add_ignore_file(&mut self, source: &str, text: &str) -> Result<(), String> {
+        self.patterns.add_file(source, text)
+    }
+
+    /// 文件是否参与比较
+    pub fn allows(&self, path: &str) -> bool {
+        self.includes(path) && self.explain(path, false).is_none_or(|m| m.negated)
+    }
+
+    /// 路径是否满足 `include` 规则，未设置 `include` 时总是满足
+    pub fn includes(&self, path: &str) -> bool {
+        self.include.is_empty() || self.include.iter().any(|rule| rule.is_match(path))
+    }
+
+    /// 目录是否被排除，为 `true` 时可跳过整个目录
+    pub fn ignores(&self, path: &str) -> bool {
+        self.explain(path, true).is_some_and(|m| !m.negated)
+    }
+
+    /// 说明路径被排除的原因，未被排除时返回 `None`
+    pub fn exclusion_reason(&self, path: &str, is_dir: bool) -> Option<String> {
+        if let Some(rule) = self.explain(path, is_dir).filter(|rule| !rule.negated) {
+            let location = match rule.line {
+                Some(line) => format!("{}:{}", rule.source, line),
+                None => rule.source,
+            };
+            return Some(format!("matches {} rule '{}'", location, rule.pattern));
+        }
+        (!is_dir && !self.includes(path)).then(|| "matches no include rule".to_string())
+    }
+
+    /// 返回决定路径是否被排除的规则，没有规则匹配时返回 `None`
+    ///
+    /// 配置文件的 `ignore` 规则总是排除路径；忽略文件中最后一条匹配的规则生效，
+    /// 与 gitignore 相同，父目录被排除时其下的路径无法再被取反规则包含。
+    pub fn explain(&self, path: &str, is_dir: bool) -> Option<FilterMatch> {
+        if let Some((text, _)) = self.ignore.iter().find(|(_, rule)| rule.is_match(path)) {
+            return Some(FilterMatch {
+                source: "ignore".to_string(),
+                line: None,
+                pattern: text.clone(),
+                negated: false,
+            });
+        }
+
+        self.patterns
+            .decide(path, is_dir)
+            .map(|pattern| FilterMatch {
+                source: pattern.source.clone(),
+                line: Some(pattern.line),
+                pattern: pattern.text.clone(),
+                negated: pattern.negated,
+            })
+    }
+}
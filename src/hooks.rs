@@ -0,0 +1,191 @@
+use serde_json::{Map, Value, json};
+use std::fs::{self, File};
+use std::path::Path;
+
+use crate::diff::hash_reader;
+use crate::path::check_path;
+
+/// 补丁中存放钩子脚本的目录，安装时不复制到目标
+pub const HOOKS_DIR: &str = ".pulonia/hooks";
+
+/// 未指定 `timeout` 时钩子的超时秒数
+pub const DEFAULT_TIMEOUT: u64 = 300;
+
+/// 钩子运行的阶段
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// 替换文件之前，失败时中止更新
+    PreApply,
+    /// 替换文件之后，失败时回滚更新
+    PostApply,
+}
+
+impl Phase {
+    /// 按运行顺序排列的所有阶段
+    pub const ALL: [Phase; 2] = [Phase::PreApply, Phase::PostApply];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Phase::PreApply => "pre_apply",
+            Phase::PostApply => "post_apply",
+        }
+    }
+}
+
+/// 钩子执行的内容
+#[derive(Debug, Clone, PartialEq)]
+pub enum HookAction {
+    /// 在目标机器上由 shell 执行的命令
+    Command(String),
+    /// 打包进补丁的脚本，`source` 为本地路径，`target` 为补丁内的路径
+    Script { source: String, target: String },
+}
+
+/// 配置文件中声明的一个钩子
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hook {
+    pub phase: Phase,
+    pub action: HookAction,
+    /// 超时秒数
+    pub timeout: u64,
+}
+
+/// 解析配置文件中的 `hooks` 表，结果按阶段和声明顺序排列
+///
+/// 每个阶段是一个列表，项为命令字符串，或含 `command` / `script` 及可选
+/// `timeout` 的表。
+pub fn parse_hooks(value: &Value) -> Result<Vec<Hook>, String> {
+    let table = match value {
+        Value::Null => return Ok(Vec::new()),
+        Value::Object(table) => table,
+        _ => return Err("'hooks' must be a table of phases".to_string()),
+    };
+    if let Some(phase) = table.keys().find(|key| {
+        !Phase::ALL
+            .iter()
+            .any(|phase| phase.name() == key.replace('-', "_"))
+    }) {
+        return Err(format!(
+            "unknown hook phase '{}', expected pre_apply or post_apply",
+            phase
+        ));
+    }
+
+    let mut hooks = Vec::new();
+    for phase in Phase::ALL {
+        let entries = table
+            .get(phase.name())
+            .or_else(|| table.get(&phase.name().replace('_', "-")));
+        let entries = match entries {
+            None | Some(Value::Null) => continue,
+            Some(Value::Array(entries)) => entries.clone(),
+            Some(entry) => vec![entry.clone()],
+        };
+        for (index, entry) in entries.iter().enumerate() {
+            let hook = parse_hook(phase, index + 1, entry)
+                .map_err(|e| format!("hooks.{}[{}]: {}", phase.name(), index, e))?;
+            hooks.push(hook);
+        }
+    }
+    Ok(hooks)
+}
+
+fn parse_hook(phase: Phase, number: usize, entry: &Value) -> Result<Hook, String> {
+    let table = match entry {
+        Value::String(command) => {
+            return Ok(Hook {
+                phase,
+                action: HookAction::Command(command.clone()),
+                timeout: DEFAULT_TIMEOUT,
+            });
+        }
+        Value::Object(table) => table,
+        _ => return Err("expected a command or a table".to_string()),
+    };
+
+    let mut action = None;
+    let mut timeout = DEFAULT_TIMEOUT;
+    for (key, value) in table {
+        match key.as_str() {
+            "command" | "script" if action.is_some() => {
+                return Err("set either 'command' or 'script', not both".to_string());
+            }
+            "command" => {
+                let command = value.as_str().ok_or("'command' must be a string")?;
+                action = Some(HookAction::Command(command.to_string()));
+            }
+            "script" => {
+                let source = value.as_str().ok_or("'script' must be a string")?;
+                let name = Path::new(source)
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .ok_or_else(|| format!("invalid script path '{}'", source))?;
+                action = Some(HookAction::Script {
+                    source: source.to_string(),
+                    target: format!("{}/{}/{}_{}", HOOKS_DIR, phase.name(), number, name),
+                });
+            }
+            "timeout" => {
+                timeout = value
+                    .as_u64()
+                    .filter(|&seconds| seconds > 0)
+                    .ok_or("'timeout' must be a positive number of seconds")?;
+            }
+            other => return Err(format!("unknown key '{}'", other)),
+        }
+    }
+    Ok(Hook {
+        phase,
+        action: action.ok_or("missing 'command' or 'script'")?,
+        timeout,
+    })
+}
+
+/// 生成迁移记录中的 `hooks` 列表，同时检查脚本是否可读
+pub fn migration_entries(hooks: &[Hook]) -> Result<Value, String> {
+    let mut entries = Vec::new();
+    for hook in hooks {
+        let mut entry = Map::new();
+        entry.insert("phase".to_string(), json!(hook.phase.name()));
+        match &hook.action {
+            HookAction::Command(command) => {
+                entry.insert("command".to_string(), json!(command));
+            }
+            HookAction::Script { source, target } => {
+                check_path(source).map_err(|e| format!("hook script '{}': {}", source, e))?;
+                let hash = File::open(source)
+                    .and_then(|mut file| hash_reader(&mut file))
+                    .map_err(|e| format!("hook script '{}': {}", source, e))?;
+                entry.insert("script".to_string(), json!(target));
+                entry.insert("hash".to_string(), json!(hash));
+            }
+        }
+        entry.insert("timeout".to_string(), json!(hook.timeout));
+        entries.push(Value::Object(entry));
+    }
+    Ok(Value::Array(entries))
+}
+
+/// 将脚本复制到补丁目录中迁移记录所列的位置
+pub fn package(hooks: &[Hook], patch_dir: &Path) -> Result<(), String> {
+    let hooks_dir = patch_dir.join(HOOKS_DIR);
+    if hooks_dir.exists() {
+        return Err(format!(
+            "the new version contains '{}', which is reserved for hook scripts",
+            HOOKS_DIR
+        ));
+    }
+    for hook in hooks {
+        if let HookAction::Script { source, target } = &hook.action {
+            let dest = patch_dir.join(target);
+            if let Some(parent) = dest.parent() {
+                fs::create_dir_all(parent)
+                    .map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?;
+            }
+            // 复制时保留权限位，使脚本在目标上可直接执行
+            fs::copy(source, &dest)
+                .map_err(|e| format!("Failed to copy hook script '{}': {}", source, e))?;
+        }
+    }
+    Ok(())
+}
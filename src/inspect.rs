@@ -0,0 +1,172 @@
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::compress::PayloadEntry;
+
+/// 补丁与迁移记录之间的不一致
+#[derive(Debug, Clone, PartialEq)]
+pub enum Issue {
+    /// 补丁中的文件没有对应的迁移记录条目
+    UnlistedPayload(String),
+    /// 迁移记录要求安装的文件不在补丁中
+    MissingPayload(String),
+    /// 补丁中文件的哈希与迁移记录不同
+    HashMismatch {
+        path: String,
+        expected: String,
+        actual: String,
+    },
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Issue::UnlistedPayload(path) => {
+                write!(f, "payload file has no migration entry: {}", path)
+            }
+            Issue::MissingPayload(path) => {
+                write!(f, "migration entry has no payload file: {}", path)
+            }
+            Issue::HashMismatch {
+                path,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "hash mismatch: {} (migration {}, payload {})",
+                path, expected, actual
+            ),
+        }
+    }
+}
+
+/// 迁移记录的概要
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Summary {
+    pub version: String,
+    /// 新增或修改的文件数
+    pub updated: usize,
+    /// 其中受保护的文件数
+    pub preserved: usize,
+    pub deleted: usize,
+    pub chmod: usize,
+    /// 需要重新打包的内层压缩包：路径 -> 格式
+    pub archives: BTreeMap<String, String>,
+    /// 各阶段的钩子数
+    pub hooks: BTreeMap<String, usize>,
+    /// 比较时使用的子目录，`(before, after)`
+    pub root: Option<(String, String)>,
+    /// 被去掉的顶层目录，`(before, after)`
+    pub strip: Option<(String, String)>,
+}
+
+/// 统计迁移记录中各类变更的数量
+pub fn summarize(migration: &Value) -> Summary {
+    let leaves = update_leaves(migration);
+    let pair = |key: &str| {
+        migration.get(key).map(|value| {
+            let side = |side: &str| value[side].as_str().unwrap_or_default().to_string();
+            (side("before"), side("after"))
+        })
+    };
+
+    let mut hooks = BTreeMap::new();
+    for hook in migration["hooks"].as_array().into_iter().flatten() {
+        let phase = hook["phase"].as_str().unwrap_or("unknown").to_string();
+        *hooks.entry(phase).or_insert(0) += 1;
+    }
+
+    Summary {
+        version: migration["version"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string(),
+        updated: leaves.len(),
+        preserved: leaves
+            .values()
+            .filter(|leaf| leaf["preserve"].as_bool() == Some(true))
+            .count(),
+        deleted: migration["deleted"].as_array().map_or(0, Vec::len),
+        chmod: migration["chmod"]
+            .as_object()
+            .map_or(0, |chmod| chmod.len()),
+        archives: migration["archives"]
+            .as_object()
+            .into_iter()
+            .flatten()
+            .map(|(path, format)| {
+                (
+                    path.clone(),
+                    format.as_str().unwrap_or_default().to_string(),
+                )
+            })
+            .collect(),
+        hooks,
+        root: pair("root"),
+        strip: pair("strip"),
+    }
+}
+
+/// 对照迁移记录检查补丁内容
+///
+/// `update` 中的每个文件和每个钩子脚本都应以记录的哈希出现在补丁中，
+/// 补丁中也不应有迁移记录未列出的文件。
+pub fn check(payload: &[PayloadEntry], migration: &Value) -> Vec<Issue> {
+    let mut expected: BTreeMap<String, String> = update_leaves(migration)
+        .into_iter()
+        .map(|(path, leaf)| (path, leaf["hash"].as_str().unwrap_or_default().to_string()))
+        .collect();
+    for hook in migration["hooks"].as_array().into_iter().flatten() {
+        if let Some(script) = hook["script"].as_str() {
+            let hash = hook["hash"].as_str().unwrap_or_default().to_string();
+            expected.insert(script.to_string(), hash);
+        }
+    }
+
+    let mut issues = Vec::new();
+    for entry in payload {
+        match expected.remove(&entry.name) {
+            None => issues.push(Issue::UnlistedPayload(entry.name.clone())),
+            Some(hash) if hash != entry.file.hash => issues.push(Issue::HashMismatch {
+                path: entry.name.clone(),
+                expected: hash,
+                actual: entry.file.hash.clone(),
+            }),
+            Some(_) => {}
+        }
+    }
+    issues.extend(expected.into_keys().map(Issue::MissingPayload));
+    issues
+}
+
+/// 展平 `update` 树，返回文件路径 -> 叶子节点
+///
+/// 叶子节点以字符串 `hash` 标识，目录节点的键都是子项名称。
+fn update_leaves(migration: &Value) -> BTreeMap<String, &Value> {
+    let mut leaves = BTreeMap::new();
+    collect_leaves(&migration["update"], "", &mut leaves);
+    leaves
+}
+
+fn collect_leaves<'a>(
+    node: &'a Value,
+    current_path: &str,
+    leaves: &mut BTreeMap<String, &'a Value>,
+) {
+    let Some(object) = node.as_object() else {
+        return;
+    };
+    if !current_path.is_empty() && object.get("hash").is_some_and(Value::is_string) {
+        leaves.insert(current_path.to_string(), node);
+        return;
+    }
+    for (name, child) in object {
+        let child_path = if current_path.is_empty() {
+            name.clone()
+        } else {
+            format!("{}/{}", current_path, name)
+        };
+        collect_leaves(child, &child_path, leaves);
+    }
+}
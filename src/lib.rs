@@ -0,0 +1,14 @@
+pub mod archive;
+pub mod audit;
+pub mod compress;
+pub mod config;
+pub mod diff;
+pub mod error;
+pub mod filter;
+pub mod hooks;
+pub mod inspect;
+pub mod limits;
+pub mod migration;
+pub mod path;
+pub mod progress;
+pub mod text;
@@ -0,0 +1,293 @@
+use std::io::Read;
+use std::path::{Component, Path};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::compress::DecompressError;
+use crate::progress::PhaseProgress;
+
+/// Uncompressed data below this size is never rejected for its compression ratio,
+/// so small but highly compressible inputs (such as sparse files) still extract.
+const RATIO_GRACE_BYTES: u64 = 1 << 20;
+
+/// Caps applied while reading input archives, to guard against compression bombs
+/// and hostile entry paths.
+#[derive(Debug, Clone)]
+pub struct ExtractLimits {
+    /// Maximum number of bytes all entries may expand to.
+    pub max_total_size: u64,
+    /// Maximum number of entries in one archive.
+    pub max_entries: u64,
+    /// Maximum uncompressed size of a single entry.
+    pub max_entry_size: u64,
+    /// Maximum ratio of uncompressed to compressed bytes.
+    pub max_ratio: u64,
+    /// Maximum number of path components of an entry.
+    pub max_depth: usize,
+    /// Maximum number of archives nested inside one another, as with `--nested`.
+    pub max_nesting: usize,
+}
+
+impl Default for ExtractLimits {
+    fn default() -> Self {
+        ExtractLimits {
+            max_total_size: 32 << 30,
+            max_entries: 1_000_000,
+            max_entry_size: 8 << 30,
+            max_ratio: 1000,
+            max_depth: 128,
+            max_nesting: 8,
+        }
+    }
+}
+
+/// Entries and bytes read so far, shared by an input archive and the archives
+/// nested in it so that the entry and total-size caps apply to all of them together.
+#[derive(Debug, Default)]
+pub struct LimitUsage {
+    entries: AtomicU64,
+    total_size: AtomicU64,
+}
+
+/// Where an archive sits among nested archives: how deep below the input it is,
+/// and the usage it shares with the archives that enclose it.
+#[derive(Debug, Clone, Default)]
+pub struct Nesting {
+    pub depth: usize,
+    pub usage: Arc<LimitUsage>,
+}
+
+impl Nesting {
+    /// The nesting of archive `name` found inside this one.
+    ///
+    /// Fails once more than `max_nesting` archives are nested inside one another.
+    pub fn enter(&self, name: &str, limits: &ExtractLimits) -> Result<Nesting, DecompressError> {
+        if self.depth >= limits.max_nesting {
+            return Err(DecompressError::NestingTooDeep {
+                name: name.to_string(),
+                limit: limits.max_nesting,
+            });
+        }
+        Ok(Nesting {
+            depth: self.depth + 1,
+            usage: self.usage.clone(),
+        })
+    }
+}
+
+/// Tracks how much of the limits an archive has used up while it is being read.
+pub struct LimitTracker<'a> {
+    limits: &'a ExtractLimits,
+    archive_size: u64,
+    /// Bytes produced by this archive alone, for the compression ratio.
+    archive_total: u64,
+    usage: Arc<LimitUsage>,
+    progress: Option<&'a PhaseProgress>,
+}
+
+impl<'a> LimitTracker<'a> {
+    pub fn new(limits: &'a ExtractLimits, archive_size: u64) -> Self {
+        LimitTracker {
+            limits,
+            archive_size,
+            archive_total: 0,
+            usage: Arc::default(),
+            progress: None,
+        }
+    }
+
+    /// Counts entries and bytes against `usage`, shared with enclosing archives.
+    pub fn sharing(mut self, usage: Arc<LimitUsage>) -> Self {
+        self.usage = usage;
+        self
+    }
+
+    /// Also reports every entry and every byte read to `progress`.
+    pub fn with_progress(mut self, progress: Option<&'a PhaseProgress>) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    /// Counts a new entry and validates its path.
+    ///
+    /// Returns the `/` separated relative name, or `None` for the archive root itself.
+    pub fn entry_name(&mut self, raw: &Path) -> Result<Option<String>, DecompressError> {
+        let entries = self.usage.entries.fetch_add(1, Ordering::Relaxed) + 1;
+        if entries > self.limits.max_entries {
+            return Err(DecompressError::TooManyEntries {
+                limit: self.limits.max_entries,
+            });
+        }
+
+        let mut parts = Vec::new();
+        for component in raw.components() {
+            match component {
+                Component::Normal(part) => parts.push(part.to_string_lossy().to_string()),
+                Component::CurDir => {}
+                _ => {
+                    return Err(DecompressError::UnsafeEntryPath(
+                        raw.to_string_lossy().to_string(),
+                    ));
+                }
+            }
+        }
+        if parts.is_empty() {
+            return Ok(None);
+        }
+        let name = parts.join("/");
+        if parts.len() > self.limits.max_depth {
+            return Err(DecompressError::PathTooDeep {
+                name,
+                limit: self.limits.max_depth,
+            });
+        }
+        if let Some(progress) = self.progress {
+            progress.entry(&name);
+        }
+        Ok(Some(name))
+    }
+
+    /// Rejects entries whose declared sizes already break the limits.
+    ///
+    /// `compressed_size` is only known for formats that compress entries individually.
+    pub fn check_declared(
+        &self,
+        name: &str,
+        size: u64,
+        compressed_size: Option<u64>,
+    ) -> Result<(), DecompressError> {
+        if size > self.limits.max_entry_size {
+            return Err(DecompressError::EntryTooLarge {
+                name: name.to_string(),
+                limit: self.limits.max_entry_size,
+            });
+        }
+        let total_size = self.usage.total_size.load(Ordering::Relaxed);
+        if total_size.saturating_add(size) > self.limits.max_total_size {
+            return Err(DecompressError::TotalSizeExceeded {
+                limit: self.limits.max_total_size,
+            });
+        }
+        if let Some(compressed_size) = compressed_size
+            && size > RATIO_GRACE_BYTES
+            && size / compressed_size.max(1) > self.limits.max_ratio
+        {
+            return Err(DecompressError::CompressionRatioExceeded {
+                name: name.to_string(),
+                limit: self.limits.max_ratio,
+            });
+        }
+        Ok(())
+    }
+
+    /// Accounts for bytes actually produced, since declared sizes can lie.
+    fn consume(&mut self, name: &str, entry_size: u64, bytes: u64) -> Result<(), DecompressError> {
+        self.archive_total += bytes;
+        let total_size = self.usage.total_size.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        if let Some(progress) = self.progress {
+            progress.advance(bytes);
+        }
+        if entry_size > self.limits.max_entry_size {
+            return Err(DecompressError::EntryTooLarge {
+                name: name.to_string(),
+                limit: self.limits.max_entry_size,
+            });
+        }
+        if total_size > self.limits.max_total_size {
+            return Err(DecompressError::TotalSizeExceeded {
+                limit: self.limits.max_total_size,
+            });
+        }
+        if self.archive_total > RATIO_GRACE_BYTES
+            && self.archive_total / self.archive_size.max(1) > self.limits.max_ratio
+        {
+            return Err(DecompressError::CompressionRatioExceeded {
+                name: name.to_string(),
+                limit: self.limits.max_ratio,
+            });
+        }
+        Ok(())
+    }
+
+    /// Wraps the data of an entry so every byte read counts against the limits.
+    pub fn reader<'t, R: Read + ?Sized>(
+        &'t mut self,
+        name: &'t str,
+        inner: &'t mut R,
+    ) -> LimitedReader<'t, 'a, R> {
+        LimitedReader {
+            inner,
+            tracker: self,
+            name,
+            entry_size: 0,
+        }
+    }
+}
+
+/// Reader returned by [`LimitTracker::reader`].
+///
+/// Limit violations surface as I/O errors wrapping a [`DecompressError`]; use
+/// [`unwrap_limit_error`] to get the typed error back.
+pub struct LimitedReader<'t, 'a, R: Read + ?Sized> {
+    inner: &'t mut R,
+    tracker: &'t mut LimitTracker<'a>,
+    name: &'t str,
+    entry_size: u64,
+}
+
+impl<R: Read + ?Sized> Read for LimitedReader<'_, '_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let bytes_read = self.inner.read(buf)?;
+        self.entry_size += bytes_read as u64;
+        self.tracker
+            .consume(self.name, self.entry_size, bytes_read as u64)
+            .map_err(std::io::Error::other)?;
+        Ok(bytes_read)
+    }
+}
+
+/// Recovers a limit violation that travelled through an I/O error.
+pub fn unwrap_limit_error(error: DecompressError) -> DecompressError {
+    match error {
+        DecompressError::Io(e)
+            if e.get_ref()
+                .is_some_and(|inner| inner.is::<DecompressError>()) =>
+        {
+            *e.into_inner()
+                .unwrap()
+                .downcast::<DecompressError>()
+                .unwrap()
+        }
+        other => other,
+    }
+}
+
+/// Checks that a link stays inside the extraction root.
+///
+/// Symbolic link targets are resolved from the directory holding the link, hard
+/// link targets (`from_root`) from the archive root.
+pub fn check_link_target(name: &str, target: &str, from_root: bool) -> Result<(), DecompressError> {
+    let escape = || DecompressError::LinkEscape {
+        name: name.to_string(),
+        target: target.to_string(),
+    };
+    let mut depth: i64 = if from_root {
+        0
+    } else {
+        name.split('/').count() as i64 - 1
+    };
+    for component in Path::new(target).components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => {}
+            Component::ParentDir => {
+                depth -= 1;
+                if depth < 0 {
+                    return Err(escape());
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => return Err(escape()),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,120 @@
+use std::fs::OpenOptions;
+use std::io::{BufWriter, Write};
+use std::sync::Mutex;
+
+use chrono::Local;
+use log::kv::{self, Key, VisitSource};
+use log::{LevelFilter, Log, Metadata, Record};
+use pulonia::error::PuloniaError;
+use pulonia::path::is_safe_output_path;
+use serde_json::{Map, Value, json};
+
+/// 日志的输出格式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogFormat {
+    /// 每行一条可读的记录
+    Text,
+    /// 每行一个 JSON 对象，结构化字段作为对象的键
+    Json,
+}
+
+/// 将 pulonia 的日志写入 stderr 或日志文件
+struct Logger {
+    level: LevelFilter,
+    format: LogFormat,
+    output: Mutex<Box<dyn Write + Send>>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        // 只记录本程序的日志，忽略依赖库的输出
+        metadata.level() <= self.level && metadata.target().starts_with("pulonia")
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = match self.format {
+            LogFormat::Text => format!(
+                "{} {:<5} {}",
+                Local::now().format("%Y-%m-%dT%H:%M:%S%.3f"),
+                record.level(),
+                record.args()
+            ),
+            LogFormat::Json => {
+                let mut fields = Map::new();
+                fields.insert("time".to_string(), json!(Local::now().to_rfc3339()));
+                fields.insert(
+                    "level".to_string(),
+                    json!(record.level().as_str().to_lowercase()),
+                );
+                fields.insert("target".to_string(), json!(record.target()));
+                fields.insert("message".to_string(), json!(record.args().to_string()));
+                let _ = record.key_values().visit(&mut JsonFields(&mut fields));
+                Value::Object(fields).to_string()
+            }
+        };
+        let mut output = self.output.lock().unwrap_or_else(|e| e.into_inner());
+        let _ = writeln!(output, "{}", line);
+    }
+
+    fn flush(&self) {
+        let _ = self
+            .output
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .flush();
+    }
+}
+
+/// 将日志记录的结构化字段加入 JSON 对象
+struct JsonFields<'a>(&'a mut Map<String, Value>);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        self.0.insert(key.to_string(), json!(value.to_string()));
+        Ok(())
+    }
+}
+
+/// 按 `-v` 出现的次数安装日志：默认只记录警告，`-v` 记录进入迁移记录的文件，
+/// `-vv` 还记录未变化和被排除的文件
+///
+/// 给出 `file` 时追加写入该文件，否则写入 stderr；该文件须位于工作目录内。
+pub fn init(verbose: u8, file: Option<&str>, format: LogFormat) -> Result<(), PuloniaError> {
+    let level = match verbose {
+        0 => LevelFilter::Warn,
+        1 => LevelFilter::Info,
+        _ => LevelFilter::Debug,
+    };
+    let output: Box<dyn Write + Send> = match file {
+        Some(path) => {
+            // 与补丁输出相同，日志文件只能写在工作目录内
+            if !is_safe_output_path(path) {
+                return Err(PuloniaError::Input(format!(
+                    "Unsafe log file path: {}. Pulonia can only write files in the current directory or its subdirectories.",
+                    path
+                )));
+            }
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(PuloniaError::io(format!(
+                    "Failed to open log file {}",
+                    path
+                )))?;
+            Box::new(BufWriter::new(file))
+        }
+        None => Box::new(std::io::stderr()),
+    };
+    let logger = Logger {
+        level,
+        format,
+        output: Mutex::new(output),
+    };
+    log::set_logger(Box::leak(Box::new(logger))).expect("the logger is installed once at start-up");
+    log::set_max_level(level);
+    Ok(())
+}
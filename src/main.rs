@@ -1,31 +1,50 @@
 use std::{
+    collections::{BTreeMap, HashMap},
     env::consts::{ARCH, OS},
-    path::Path,
+    io::IsTerminal,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::Arc,
 };
 
 use chrono::Local;
-use clap::Parser;
+use clap::{CommandFactory, FromArgMatches};
+use globset::Glob;
 use tempfile::TempDir;
 
 mod cli;
-use cli::Cli;
-mod compress;
-use compress::decompress;
+mod logging;
+mod terminal;
+use cli::{Cli, Command, ConfigAction, DiffArgs};
+use pulonia::audit::{self, Decision};
+use pulonia::compress::{self, decompress};
+use pulonia::config::{self, Config};
+use pulonia::error::{self, PuloniaError};
+use pulonia::filter::{self, PathFilter};
+use pulonia::hooks;
+use pulonia::progress;
+use pulonia::{diff, inspect, migration, text};
 
-mod diff;
-mod migration;
-mod path;
+use pulonia::path::check_path;
 
-use path::check_path;
+use pulonia::diff::{HashOptions, build_hash_tree, get_hash};
+use pulonia::limits::ExtractLimits;
+use pulonia::migration::generate_migration;
 
-use crate::diff::get_hash;
-use crate::migration::generate_migration;
-
-fn main() {
-    pulonia_init();
+fn main() -> ExitCode {
+    let result = pulonia_init();
+    log::logger().flush();
+    match result {
+        Ok(code) => ExitCode::from(code),
+        Err(err) => {
+            eprintln!("{}", err);
+            ExitCode::from(err.exit_code())
+        }
+    }
 }
 
-fn pulonia_init() {
+/// 运行命令，返回退出码
+fn pulonia_init() -> Result<u8, PuloniaError> {
     println!("{}", "-".repeat(60));
     println!("Pulonia started");
     println!("version: {}", env!("CARGO_PKG_VERSION"));
@@ -35,40 +54,201 @@ fn pulonia_init() {
     println!("arch: {}", ARCH);
     println!("{}", "-".repeat(60));
 
-    let cli = Cli::parse();
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+
+    // 优先级：命令行 > 环境变量 > 配置档案 > 配置文件 > 默认值
+    let (mut config, skipped_env) = load_config(cli.config.as_deref(), cli.profile.as_deref())?;
+    config.options.extend(cli::command_line_options(&matches));
+
+    // 日志选项也可以来自配置文件和环境变量，因此在合并设置后才安装日志
+    let log = cli::resolve_log(&config.options).map_err(PuloniaError::Config)?;
+    logging::init(log.verbose, log.log_file.as_deref(), log.log_format)?;
+    for name in skipped_env {
+        log::warn!(
+            "Ignoring environment variable {}: not a pulonia option",
+            name
+        );
+    }
+    // 只在终端中显示进度条，重定向到文件或管道时保持输出整洁；
+    // 详细日志写入 stderr 时也不显示，以免与日志交错
+    let logs_to_stderr = log.verbose > 0 && log.log_file.is_none();
+    if !log.quiet && !logs_to_stderr && std::io::stderr().is_terminal() {
+        progress::set_reporter(Arc::new(terminal::TerminalProgress::default()));
+    }
+
+    match &cli.command {
+        Some(Command::Config {
+            action: ConfigAction::Show,
+        }) => Ok(show_config(&config)),
+        Some(Command::CheckIgnore { paths }) => Ok(check_ignore(&base_filter(&config)?, paths)),
+        Some(Command::Inspect {
+            patch,
+            migration,
+            format,
+        }) => inspect(patch, migration.as_deref(), format.as_deref()),
+        // 未给出子命令时与 `diff` 相同；只有比较时才需要校验 `diff` 的选项
+        Some(Command::Diff(_)) | None => {
+            let args = cli::resolve(&config.options).map_err(PuloniaError::Config)?;
+            diff(args, &config)
+        }
+    }
+}
+
+/// 列出补丁中的文件，给出迁移记录时输出概要并检查两者是否一致
+///
+/// 发现不一致时以 1 退出。
+fn inspect(
+    patch: &str,
+    migration_path: Option<&str>,
+    format: Option<&str>,
+) -> Result<u8, PuloniaError> {
+    check_path(patch).map_err(PuloniaError::path("patch"))?;
+    let format = compress::detect_format(patch, format)
+        .map_err(PuloniaError::decompress("Failed to detect patch format"))?;
+    let payload = compress::list_archive(patch, Some(&format), &ExtractLimits::default())
+        .map_err(PuloniaError::decompress("Failed to read patch"))?;
+
+    let total: u64 = payload.iter().map(|entry| entry.size).sum();
+    println!("Patch: {} ({})", patch, format);
+    println!("Entries: {} files, {} bytes", payload.len(), total);
+    for entry in &payload {
+        let link = entry
+            .file
+            .link
+            .as_ref()
+            .map(|target| format!(" -> {}", target))
+            .unwrap_or_default();
+        println!(
+            "{:>12}  {}  {}{}",
+            entry.size, entry.file.hash, entry.name, link
+        );
+    }
+
+    let Some(migration_path) = migration_path else {
+        return Ok(error::EXIT_OK);
+    };
+    let text = std::fs::read_to_string(migration_path).map_err(PuloniaError::io(format!(
+        "Failed to read migration record {}",
+        migration_path
+    )))?;
+    let migration: serde_json::Value = serde_json::from_str(&text).map_err(|err| {
+        PuloniaError::Input(format!(
+            "Invalid migration record {}: {}",
+            migration_path, err
+        ))
+    })?;
+
+    let summary = inspect::summarize(&migration);
+    println!("{}", "-".repeat(60));
+    println!(
+        "Migration: {} (version {})",
+        migration_path, summary.version
+    );
+    println!(
+        "Updated: {} ({} preserved)",
+        summary.updated, summary.preserved
+    );
+    println!("Deleted: {}", summary.deleted);
+    println!("Permission changes: {}", summary.chmod);
+    for (archive, archive_format) in &summary.archives {
+        println!("Nested archive: {} ({})", archive, archive_format);
+    }
+    for phase in hooks::Phase::ALL {
+        if let Some(count) = summary.hooks.get(phase.name()) {
+            println!("Hooks {}: {}", phase.name(), count);
+        }
+    }
+    if let Some((before, after)) = &summary.root {
+        println!("Root: {} -> {}", before, after);
+    }
+    if let Some((before, after)) = &summary.strip {
+        println!("Stripped folders: {} -> {}", before, after);
+    }
 
-    if cli.after_path.is_empty() || cli.before_path.is_empty() {
-        eprintln!("Error: Both current and previous version paths must be provided.");
-        return;
+    let issues = inspect::check(&payload, &migration);
+    println!("{}", "-".repeat(60));
+    if issues.is_empty() {
+        println!("The patch matches the migration record.");
+        return Ok(error::EXIT_OK);
     }
+    for issue in &issues {
+        eprintln!("{}", issue);
+    }
+    eprintln!("Found {} inconsistencies.", issues.len());
+    Ok(error::EXIT_ISSUES_FOUND)
+}
+
+/// 比较两个版本，生成迁移记录和补丁
+///
+/// 两个版本相同时返回 `EXIT_IDENTICAL`，不写出任何文件。
+fn diff(args: DiffArgs, config: &Config) -> Result<u8, PuloniaError> {
+    let filter = base_filter(config)?;
+
+    let preserve = config.preserve_set().map_err(PuloniaError::Config)?;
+    // 在比较之前检查钩子脚本，避免比较完成后才报错
+    let hook_entries = hooks::migration_entries(&config.hooks).map_err(PuloniaError::Config)?;
 
-    let temp_dir = match cli.temp_dir_path {
+    let provided = |path: &Option<String>| path.clone().filter(|path| !path.is_empty());
+    let (Some(before_path), Some(after_path)) =
+        (provided(&args.before_path), provided(&args.after_path))
+    else {
+        return Err(PuloniaError::Input(
+            "Error: Both current and previous version paths must be provided.".to_string(),
+        ));
+    };
+
+    let temp_dir = match args.temp_dir_path {
         Some(path) => {
-            check_path(&path).unwrap_or_else(|err| {
-                eprintln!("Invalid temporary directory path: {}", err);
-                std::process::exit(1);
-            });
-            TempDir::new_in(path).unwrap()
+            check_path(&path).map_err(PuloniaError::path("temporary directory"))?;
+            TempDir::new_in(path)
         }
-        None => TempDir::new().unwrap(),
+        None => TempDir::new(),
+    }
+    .map_err(PuloniaError::io("Failed to create temporary directory"))?;
+
+    check_path(&after_path).map_err(PuloniaError::path("current version"))?;
+    check_path(&before_path).map_err(PuloniaError::path("previous version"))?;
+
+    let mut hash_options = HashOptions {
+        filter,
+        ..Default::default()
     };
+    for rule in &args.normalize {
+        let rule = text::TextRule::parse(rule)
+            .map_err(|err| PuloniaError::Input(format!("Invalid normalisation rule: {}", err)))?;
+        hash_options.text_rules.push(rule);
+    }
 
-    check_path(&cli.after_path).unwrap_or_else(|err| {
-        eprintln!("Invalid current version path: {}", err);
-        std::process::exit(1);
-    });
+    // 未指定的上限沿用默认值
+    let defaults = ExtractLimits::default();
+    let limits = ExtractLimits {
+        max_total_size: args.max_total_size.unwrap_or(defaults.max_total_size),
+        max_entries: args.max_entries.unwrap_or(defaults.max_entries),
+        max_entry_size: args.max_entry_size.unwrap_or(defaults.max_entry_size),
+        max_ratio: args.max_ratio.unwrap_or(defaults.max_ratio),
+        max_depth: args.max_depth.unwrap_or(defaults.max_depth),
+        max_nesting: args.max_nesting.unwrap_or(defaults.max_nesting),
+    };
 
-    check_path(&cli.before_path).unwrap_or_else(|err| {
-        eprintln!("Invalid previous version path: {}", err);
-        std::process::exit(1);
-    });
+    // 匹配的内层压缩包按目录展开比较
+    for pattern in &args.nested {
+        let glob = Glob::new(pattern).map_err(|err| {
+            PuloniaError::Input(format!(
+                "Invalid nested archive glob '{}': {}",
+                pattern, err
+            ))
+        })?;
+        hash_options.nested.push(glob.compile_matcher());
+    }
+    hash_options.limits = limits.clone();
 
-    let output_path = cli.output_path.unwrap_or_else(|| "ota".to_string());
+    let output_path = args.output_path.unwrap_or_else(|| "ota".to_string());
 
     // 先检查用户是否指定了格式
-    let format_specified = cli.format.is_some();
+    let format_specified = args.format.is_some();
 
-    let format = cli.format.unwrap_or_else(|| {
+    let format = args.format.unwrap_or_else(|| {
         Path::new(&output_path)
             .extension()
             .and_then(|ext| ext.to_str())
@@ -103,8 +283,30 @@ fn pulonia_init() {
         output_path
     };
 
-    println!("after path: {}", cli.after_path);
-    println!("before path: {}", cli.before_path);
+    // 在比较之前检查补丁格式和压缩选项，避免写出迁移记录后才报错
+    let compress_options = compress::CompressOptions {
+        level: args.level,
+        method: args.method,
+        long_distance: args.long_distance,
+        store_extensions: args.store_extensions.unwrap_or_else(|| {
+            compress::DEFAULT_STORE_EXTENSIONS
+                .iter()
+                .map(|ext| ext.to_string())
+                .collect()
+        }),
+        min_saving: args.min_saving,
+    };
+    compress::validate_options(&format, &compress_options)
+        .map_err(PuloniaError::decompress("Invalid patch options"))?;
+
+    // 优先使用用户指定的格式，否则根据文件头和扩展名识别
+    let after_format = compress::detect_format(&after_path, args.after_format.as_deref())
+        .map_err(PuloniaError::decompress("Invalid current version archive"))?;
+    let before_format = compress::detect_format(&before_path, args.before_format.as_deref())
+        .map_err(PuloniaError::decompress("Invalid previous version archive"))?;
+
+    println!("after path: {} ({})", after_path, after_format);
+    println!("before path: {} ({})", before_path, before_format);
     println!("Temporary directory: {}", temp_dir.path().display());
     println!("Output path: {}", output_path);
     println!("Patch format: {}", format);
@@ -113,18 +315,194 @@ fn pulonia_init() {
 
     let decompressed_after_path = Path::join(temp_dir.path(), "after_decompressed");
     let decompressed_before_path = Path::join(temp_dir.path(), "before_decompressed");
-    decompress(&cli.after_path, decompressed_after_path.to_str().unwrap()).unwrap();
-    decompress(&cli.before_path, decompressed_before_path.to_str().unwrap()).unwrap();
 
-    let before_hash = get_hash(decompressed_before_path);
-    let after_hash = get_hash(decompressed_after_path.clone());
+    // 两个输入都是 zip 时才能使用中央目录中的 CRC32 和大小做预筛选
+    let zip_prefilter = args.zip_prefilter && before_format == "zip" && after_format == "zip";
+    if args.zip_prefilter && !zip_prefilter {
+        println!("Zip pre-filter skipped: both inputs must be zip files.");
+    }
+    let in_memory = args.stream || zip_prefilter;
+
+    // 只比较输入中的指定子目录
+    let subtree = |root: &Option<String>, side: &str| {
+        root.as_deref()
+            .map(|root| {
+                compress::normalize_subtree(root)
+                    .map_err(PuloniaError::decompress(format!("Invalid {} root", side)))
+            })
+            .transpose()
+    };
+    let before_subtree = subtree(&args.before_root, "previous version")?;
+    let after_subtree = subtree(&args.after_root, "current version")?;
+
+    // 目录输入直接使用，压缩包输入解压到临时目录
+    let input_root = |input_path: &str, format: &str, subtree: &Option<String>, temp: PathBuf| {
+        if format != "dir" {
+            return Ok(temp);
+        }
+        let root = PathBuf::from(input_path).join(subtree.as_deref().unwrap_or_default());
+        if !root.is_dir() {
+            return Err(PuloniaError::Input(format!(
+                "Directory {} not found",
+                root.display()
+            )));
+        }
+        Ok(root)
+    };
+    let before_root = input_root(
+        &before_path,
+        &before_format,
+        &before_subtree,
+        decompressed_before_path,
+    )?;
+    let after_root = input_root(
+        &after_path,
+        &after_format,
+        &after_subtree,
+        decompressed_after_path,
+    )?;
+
+    // 输入根目录中的忽略规则同时作用于两侧，新版本的规则优先
+    if args.archive_ignore {
+        for (input_path, format, root, subtree) in [
+            (&before_path, &before_format, &before_root, &before_subtree),
+            (&after_path, &after_format, &after_root, &after_subtree),
+        ] {
+            let content = if format == "dir" {
+                std::fs::read(root.join(filter::IGNORE_FILE)).ok()
+            } else {
+                compress::read_entry(
+                    input_path,
+                    Some(format),
+                    filter::IGNORE_FILE,
+                    &limits,
+                    subtree.as_deref(),
+                )
+                .map_err(PuloniaError::decompress(format!(
+                    "Failed to read {}",
+                    input_path
+                )))?
+            };
+            if let Some(content) = content {
+                let source = format!("{}:{}", input_path, filter::IGNORE_FILE);
+                println!("Ignore rules: {}", source);
+                hash_options
+                    .filter
+                    .add_ignore_file(&source, &String::from_utf8_lossy(&content))
+                    .map_err(|err| PuloniaError::Input(format!("Invalid ignore file: {}", err)))?;
+            }
+        }
+    }
+
+    let (before_hash, after_hash) = if zip_prefilter {
+        let (before_files, after_files) = compress::hash_zip_pair(
+            &before_path,
+            &after_path,
+            &hash_options,
+            &limits,
+            (before_subtree.as_deref(), after_subtree.as_deref()),
+        )
+        .map_err(PuloniaError::decompress("Failed to read input archives"))?;
+        (
+            build_hash_tree("before", &before_files),
+            build_hash_tree("after", &after_files),
+        )
+    } else {
+        (
+            hash_input(
+                &before_path,
+                &before_format,
+                &before_root,
+                args.stream,
+                &hash_options,
+                &limits,
+                before_subtree.as_deref(),
+            )?,
+            hash_input(
+                &after_path,
+                &after_format,
+                &after_root,
+                args.stream,
+                &hash_options,
+                &limits,
+                after_subtree.as_deref(),
+            )?,
+        )
+    };
+
+    let before_inner = manifest_root(&before_hash);
+    let after_inner = manifest_root(&after_hash);
 
-    let before_inner = before_hash.as_object().unwrap().values().next().unwrap();
-    let after_inner = after_hash.as_object().unwrap().values().next().unwrap();
+    // 去掉顶层目录，使带版本号根目录的两个压缩包能够对齐
+    let (before_inner, before_prefix, after_inner, after_prefix) = match args.strip_components {
+        Some(count) => {
+            let strip = |node, side| {
+                diff::strip_components(node, count).map_err(PuloniaError::diff(format!(
+                    "Invalid {} version layout",
+                    side
+                )))
+            };
+            let (before_node, before_prefix) = strip(before_inner, "previous")?;
+            let (after_node, after_prefix) = strip(after_inner, "current")?;
+            (before_node, before_prefix, after_node, after_prefix)
+        }
+        None => match (
+            diff::single_root(before_inner),
+            diff::single_root(after_inner),
+        ) {
+            (Some((before_name, before_node)), Some((after_name, after_node)))
+                if before_name != after_name =>
+            {
+                // 名称不同的目录未必是同一目录的两个版本，只在名称只差版本号，
+                // 或用户已用子目录参数指定比较范围时才自动对齐
+                let explicit_root = args.before_root.is_some() || args.after_root.is_some();
+                if diff::is_version_pair(before_name, after_name) || explicit_root {
+                    audit::record(
+                        Decision::Aligned,
+                        after_name,
+                        &format!(
+                            "compared with previous top-level folder {}: {}",
+                            before_name,
+                            if explicit_root {
+                                "sub-tree root given"
+                            } else {
+                                "names differ only in their version numbers"
+                            }
+                        ),
+                    );
+                    (
+                        before_node,
+                        before_name.to_string(),
+                        after_node,
+                        after_name.to_string(),
+                    )
+                } else {
+                    audit::record(
+                        Decision::Unaligned,
+                        after_name,
+                        &format!(
+                            "not compared with previous top-level folder {}: names are not versions of each other; pass --strip-components 1 to compare them as one tree",
+                            before_name
+                        ),
+                    );
+                    (before_inner, String::new(), after_inner, String::new())
+                }
+            }
+            _ => (before_inner, String::new(), after_inner, String::new()),
+        },
+    };
+    if !before_prefix.is_empty() || !after_prefix.is_empty() {
+        println!(
+            "Aligned top-level folders: {} -> {}",
+            before_prefix, after_prefix
+        );
+    }
 
-    if before_inner == after_inner {
+    // 生成迁移记录文件，是否相同以迁移记录为准，而不是清单的哈希
+    let mut changes = generate_migration(before_inner, after_inner, &preserve);
+    if !migration::has_changes(&changes) {
         println!("The two files are identical.");
-        return;
+        return Ok(error::EXIT_IDENTICAL);
     }
     eprintln!("The hash of the two files is different.");
     eprintln!("before hash: {}", before_inner);
@@ -132,59 +510,412 @@ fn pulonia_init() {
 
     println!("{}", "-".repeat(60));
 
-    // 生成迁移记录文件
-    let changes = generate_migration(before_inner, after_inner);
+    if before_subtree.is_some() || after_subtree.is_some() {
+        // 记录比较范围，路径均相对于各自的子目录
+        changes["root"] = serde_json::json!({
+            "before": before_subtree.as_deref().unwrap_or_default(),
+            "after": after_subtree.as_deref().unwrap_or_default()
+        });
+    }
+    if !before_prefix.is_empty() || !after_prefix.is_empty() {
+        // 记录被去掉的前缀，以便应用补丁时映射回原路径
+        changes["strip"] = serde_json::json!({
+            "before": before_prefix,
+            "after": after_prefix
+        });
+    }
+
+    if !config.hooks.is_empty() {
+        // 安装补丁前后运行的钩子，脚本随补丁分发
+        changes["hooks"] = hook_entries;
+    }
 
     // 保存迁移记录文件
     let migration_file_path = format!("migration_{}.json", Local::now().format("%y%m%d_%H%M"));
-    let json_string = serde_json::to_string_pretty(&changes).unwrap();
-    match std::fs::write(&migration_file_path, json_string) {
-        Ok(_) => {
-            println!("Migration report saved to: {}", migration_file_path);
-        }
-        Err(e) => {
-            eprintln!("Failed to save migration report: {}", e);
-        }
-    }
+    let json_string =
+        serde_json::to_string_pretty(&changes).expect("a JSON value always serialises");
+    std::fs::write(&migration_file_path, json_string)
+        .map_err(PuloniaError::io("Failed to save migration report"))?;
+    println!("Migration report saved to: {}", migration_file_path);
 
     // 获取更新的文件列表并打包
     let updated_files = migration::get_updated_files(before_inner, after_inner);
-    if !updated_files.is_empty() {
+    let has_scripts = config
+        .hooks
+        .iter()
+        .any(|hook| matches!(hook.action, hooks::HookAction::Script { .. }));
+    if !updated_files.is_empty() || has_scripts {
         let patch_temp_dir = temp_dir.path().join("patch_temp");
-        if let Err(e) = std::fs::create_dir_all(&patch_temp_dir) {
-            eprintln!("Failed to create patch temp directory: {}", e);
-            return;
-        }
+        std::fs::create_dir_all(&patch_temp_dir)
+            .map_err(PuloniaError::io("Failed to create patch temp directory"))?;
+        // 补丁内的路径不带被去掉的前缀
+        let patch_dir = patch_temp_dir.join(&after_prefix);
+
+        // 内层压缩包中的文件单独解压，其余文件按原方式收集
+        let archives = migration::nested_archives(after_inner);
+        let (updated_files, nested_files) = split_nested(updated_files, &archives);
+
+        if updated_files.is_empty() {
+            // 所有变化都位于内层压缩包中
+        } else if in_memory && after_format != "dir" {
+            // 流式模式下只解压需要放入补丁的文件
+            compress::extract_entries(
+                &after_path,
+                Some(&after_format),
+                path_str(&patch_temp_dir)?,
+                &updated_files
+                    .iter()
+                    .map(|file_path| prefixed(&after_prefix, file_path))
+                    .collect::<Vec<_>>(),
+                &limits,
+                after_subtree.as_deref(),
+            )
+            .map_err(PuloniaError::decompress("Failed to extract updated files"))?;
+        } else {
+            for file_path in updated_files {
+                let src_path = after_root.join(&after_prefix).join(&file_path);
+                let dest_path = patch_dir.join(&file_path);
 
-        for file_path in updated_files {
-            let src_path = decompressed_after_path.join(&file_path);
-            let dest_path = patch_temp_dir.join(&file_path);
+                if let Some(parent) = dest_path.parent() {
+                    std::fs::create_dir_all(parent).map_err(PuloniaError::io(format!(
+                        "Failed to create directory {}",
+                        parent.display()
+                    )))?;
+                }
 
-            if let Some(parent) = dest_path.parent()
-                && let Err(e) = std::fs::create_dir_all(parent) {
-                    eprintln!("Failed to create directory: {} - {}", parent.display(), e);
-                    continue;
+                // 符号链接按链接复制，而不是复制其指向的文件
+                if let Ok(target) = std::fs::read_link(&src_path) {
+                    compress::create_symlink(&target, &dest_path).map_err(PuloniaError::io(
+                        format!(
+                            "Failed to create symlink {} -> {}",
+                            dest_path.display(),
+                            target.display()
+                        ),
+                    ))?;
+                } else {
+                    std::fs::copy(&src_path, &dest_path).map_err(PuloniaError::io(format!(
+                        "Failed to copy file {} to {}",
+                        src_path.display(),
+                        dest_path.display()
+                    )))?;
                 }
+            }
+        }
+
+        if !nested_files.is_empty() {
+            let after = AfterInput {
+                path: &after_path,
+                format: &after_format,
+                in_memory,
+                root: &after_root,
+                prefix: &after_prefix,
+                subtree: after_subtree.as_deref(),
+            };
+            extract_nested_files(
+                &nested_files,
+                &archives,
+                &after,
+                &temp_dir.path().join("nested"),
+                &patch_dir,
+                &limits,
+            )?;
+        }
+
+        hooks::package(&config.hooks, &patch_dir).map_err(|err| {
+            PuloniaError::Input(format!("Failed to package hook scripts: {}", err))
+        })?;
+
+        compress::compress(
+            path_str(&patch_dir)?,
+            &output_path,
+            &format,
+            &compress_options,
+        )
+        .map_err(PuloniaError::decompress("Failed to create patch file"))?;
+        println!("Patch file created successfully at: {}", output_path);
+    } else {
+        println!("No files updated, skipping patch generation.");
+    }
+    Ok(error::EXIT_OK)
+}
+
+/// 读取配置文件并应用配置档案和环境变量，未指定时依次查找工作目录中的默认配置文件
+///
+/// 同时返回被跳过的环境变量名，待日志安装后再给出警告。
+fn load_config(
+    config_path: Option<&str>,
+    profile: Option<&str>,
+) -> Result<(Config, Vec<String>), PuloniaError> {
+    let config_path = config_path
+        .map(str::to_string)
+        .or_else(|| std::env::var(config::CONFIG_ENV).ok())
+        .map(PathBuf::from)
+        .or_else(config::discover);
+    let profile = profile
+        .map(str::to_string)
+        .or_else(|| std::env::var(config::PROFILE_ENV).ok())
+        .filter(|profile| !profile.is_empty());
+
+    let mut config = match &config_path {
+        Some(path) => {
+            println!("Config: {}", path.display());
+            Config::load(path, profile.as_deref()).map_err(PuloniaError::Config)?
+        }
+        None if profile.is_some() => {
+            return Err(PuloniaError::Config(
+                "a profile requires a configuration file".to_string(),
+            ));
+        }
+        None => Config::default(),
+    };
+    if let Some(profile) = &profile {
+        println!("Profile: {}", profile);
+    }
+    let skipped = config
+        .apply_env(std::env::vars(), cli::is_option)
+        .map_err(PuloniaError::Config)?;
+    Ok((config, skipped))
+}
+
+/// 以 TOML 形式输出合并后的设置，每个取值后注明来源
+fn show_config(config: &Config) -> u8 {
+    println!();
+    for (key, setting) in &config.options {
+        let value = cli::typed_value(key, &setting.value);
+        println!("{} = {}  # {}", key, value, setting.source);
+    }
+    for (key, rules) in [
+        ("ignore", &config.ignore),
+        ("include", &config.include),
+        ("preserve", &config.preserve),
+    ] {
+        if !rules.is_empty() {
+            println!("{} = {}", key, serde_json::json!(rules));
+        }
+    }
+    for hook in &config.hooks {
+        let action = match &hook.action {
+            hooks::HookAction::Command(command) => format!("command {:?}", command),
+            hooks::HookAction::Script { source, .. } => format!("script {:?}", source),
+        };
+        println!(
+            "# hook {}: {}, timeout {}s",
+            hook.phase.name(),
+            action,
+            hook.timeout
+        );
+    }
+    error::EXIT_OK
+}
+
+/// 由配置文件和工作目录中的 .puloniaignore 构建路径过滤
+fn base_filter(config: &Config) -> Result<PathFilter, PuloniaError> {
+    let mut filter = config.path_filter().map_err(PuloniaError::Config)?;
+    if let Ok(text) = std::fs::read_to_string(filter::IGNORE_FILE) {
+        println!("Ignore rules: {}", filter::IGNORE_FILE);
+        filter
+            .add_ignore_file(filter::IGNORE_FILE, &text)
+            .map_err(|err| PuloniaError::Input(format!("Invalid ignore file: {}", err)))?;
+    }
+    Ok(filter)
+}
+
+/// 说明每个路径由哪条规则决定是否参与比较
+///
+/// 与 `git check-ignore` 相同，有路径被排除时以 0 退出，否则以 1 退出。
+fn check_ignore(filter: &PathFilter, paths: &[String]) -> u8 {
+    let mut any_ignored = false;
+    for path in paths {
+        let is_dir = path.ends_with('/');
+        let name = path.trim_start_matches("./").trim_matches('/');
+        let rule = filter.explain(name, is_dir);
+        let ignored =
+            rule.as_ref().is_some_and(|rule| !rule.negated) || (!is_dir && !filter.includes(name));
+        any_ignored |= ignored;
 
-            if let Err(e) = std::fs::copy(&src_path, &dest_path) {
-                eprintln!(
-                    "Failed to copy file: {} to {} - {}",
-                    src_path.display(),
-                    dest_path.display(),
-                    e
-                );
+        let status = if ignored { "ignored" } else { "not ignored" };
+        match rule {
+            Some(rule) => {
+                let location = match rule.line {
+                    Some(line) => format!("{}:{}", rule.source, line),
+                    None => rule.source,
+                };
+                println!("{}: {} ({}: {})", status, path, location, rule.pattern);
             }
+            None if ignored => println!("{}: {} (include: no rule matches)", status, path),
+            None => println!("{}: {}", status, path),
         }
+    }
+    if any_ignored {
+        error::EXIT_OK
+    } else {
+        error::EXIT_NOT_IGNORED
+    }
+}
+
+/// 当前版本输入的位置，补丁中的文件从这里取出
+struct AfterInput<'a> {
+    path: &'a str,
+    format: &'a str,
+    /// 流式模式下输入没有解压，直接从压缩包中读取
+    in_memory: bool,
+    root: &'a Path,
+    prefix: &'a str,
+    subtree: Option<&'a str>,
+}
 
-        match compress::compress(patch_temp_dir.to_str().unwrap(), &output_path, &format) {
-            Ok(_) => {
-                println!("Patch file created successfully at: {}", output_path);
+/// 从内层压缩包中取出有变化的文件，放到补丁目录中该压缩包的路径下
+///
+/// 内层压缩包先逐层取出到 `work_dir`，再从中解压需要的文件。
+fn extract_nested_files(
+    nested_files: &BTreeMap<String, Vec<String>>,
+    archives: &BTreeMap<String, String>,
+    after: &AfterInput,
+    work_dir: &Path,
+    patch_dir: &Path,
+    limits: &ExtractLimits,
+) -> Result<(), PuloniaError> {
+    // 先得到外层压缩包，再从中取出内层压缩包，因此按路径长度排序
+    let mut needed: Vec<&String> = archives
+        .keys()
+        .filter(|archive| {
+            nested_files
+                .keys()
+                .any(|file| *file == **archive || file.starts_with(&format!("{}/", archive)))
+        })
+        .collect();
+    needed.sort_by_key(|archive| archive.len());
+
+    let mut archive_files: HashMap<&str, PathBuf> = HashMap::new();
+    for (index, archive) in needed.into_iter().enumerate() {
+        let dest = work_dir.join(index.to_string());
+        let result = match owning_archive(archive, archives) {
+            Some(parent) => {
+                let name = archive[parent.len() + 1..].to_string();
+                compress::extract_entries(
+                    path_str(&archive_files[parent])?,
+                    Some(&archives[parent]),
+                    path_str(&dest)?,
+                    std::slice::from_ref(&name),
+                    limits,
+                    None,
+                )
+                .map(|_| dest.join(name))
             }
-            Err(e) => {
-                eprintln!("Failed to create patch file: {}", e);
+            None if after.in_memory && after.format != "dir" => {
+                let name = prefixed(after.prefix, archive);
+                compress::extract_entries(
+                    after.path,
+                    Some(after.format),
+                    path_str(&dest)?,
+                    std::slice::from_ref(&name),
+                    limits,
+                    after.subtree,
+                )
+                .map(|_| dest.join(name))
             }
+            None => Ok(after.root.join(after.prefix).join(archive)),
+        };
+        let file = result.map_err(PuloniaError::decompress(format!(
+            "Failed to read nested archive {}",
+            archive
+        )))?;
+        archive_files.insert(archive, file);
+    }
+
+    for (archive, files) in nested_files {
+        compress::extract_entries(
+            path_str(&archive_files[archive.as_str()])?,
+            Some(&archives[archive]),
+            path_str(&patch_dir.join(archive))?,
+            files,
+            limits,
+            None,
+        )
+        .map_err(PuloniaError::decompress(format!(
+            "Failed to extract files of {}",
+            archive
+        )))?;
+    }
+    Ok(())
+}
+
+/// 找出包含该路径的最内层压缩包
+fn owning_archive<'a>(path: &str, archives: &'a BTreeMap<String, String>) -> Option<&'a str> {
+    archives
+        .keys()
+        .filter(|archive| path.starts_with(&format!("{}/", archive)))
+        .max_by_key(|archive| archive.len())
+        .map(|archive| archive.as_str())
+}
+
+/// 将更新的文件分为外层文件和各内层压缩包中的文件（相对于压缩包的路径）
+fn split_nested(
+    files: Vec<String>,
+    archives: &BTreeMap<String, String>,
+) -> (Vec<String>, BTreeMap<String, Vec<String>>) {
+    let mut outer = Vec::new();
+    let mut nested: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for file in files {
+        match owning_archive(&file, archives) {
+            Some(archive) => nested
+                .entry(archive.to_string())
+                .or_default()
+                .push(file[archive.len() + 1..].to_string()),
+            None => outer.push(file),
         }
+    }
+    (outer, nested)
+}
+
+/// 计算单个输入的清单
+///
+/// 目录直接哈希；压缩包在流式模式下于内存中哈希，否则先解压到 `root` 再哈希。
+/// 指定 `subtree` 时只处理压缩包中该子目录下的条目。
+fn hash_input(
+    input_path: &str,
+    format: &str,
+    root: &Path,
+    stream: bool,
+    options: &HashOptions,
+    limits: &ExtractLimits,
+    subtree: Option<&str>,
+) -> Result<serde_json::Value, PuloniaError> {
+    let context = format!("Failed to read {}", input_path);
+    if format == "dir" {
+        return get_hash(root.to_path_buf(), options).map_err(PuloniaError::diff(context));
+    }
+    if stream {
+        let files = compress::hash_archive(input_path, Some(format), options, limits, subtree)
+            .map_err(PuloniaError::decompress(context))?;
+        return Ok(build_hash_tree("root", &files));
+    }
+    decompress(input_path, path_str(root)?, Some(format), limits, subtree)
+        .map_err(PuloniaError::decompress(context.clone()))?;
+    get_hash(root.to_path_buf(), options).map_err(PuloniaError::diff(context))
+}
+
+/// 清单只有一个顶层节点，即输入本身
+fn manifest_root(manifest: &serde_json::Value) -> &serde_json::Value {
+    manifest
+        .as_object()
+        .and_then(|root| root.values().next())
+        .expect("a manifest has a single root node")
+}
+
+/// 压缩包接口只接受 UTF-8 路径
+fn path_str(path: &Path) -> Result<&str, PuloniaError> {
+    path.to_str().ok_or_else(|| PuloniaError::Path {
+        what: "temporary".to_string(),
+        reason: format!("{} is not valid UTF-8", path.display()),
+    })
+}
+
+/// 在相对路径前加上目录前缀，前缀为空时保持不变
+fn prefixed(prefix: &str, path: &str) -> String {
+    if prefix.is_empty() {
+        path.to_string()
     } else {
-        println!("No files updated, skipping patch generation.");
+        format!("{}/{}", prefix, path)
     }
 }
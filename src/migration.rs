@@ -1,43 +1,166 @@
 use serde_json::{Value, json};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::audit::{self, Decision};
+use crate::filter::PatternSet;
 
 /// 根据文档中的迁移协议 v1 生成迁移记录
-pub fn generate_migration(before_inner: &Value, after_inner: &Value) -> Value {
+///
+/// 匹配 `preserve` 的路径从不出现在 `deleted` 和 `chmod` 中，新增或修改时在
+/// `update` 中标记 `"preserve": true`，表示仅在目标缺失该文件时安装。
+/// 对每个文件的决定及原因记录在 [`audit`] 日志中。
+pub fn generate_migration(
+    before_inner: &Value,
+    after_inner: &Value,
+    preserve: &PatternSet,
+) -> Value {
     let before_files = flatten_to_map(before_inner, String::new());
     let after_files = flatten_to_map(after_inner, String::new());
 
     let mut update = json!({});
     let mut deleted = Vec::new();
+    let mut chmod = serde_json::Map::new();
+    let mut changed_paths = Vec::new();
 
     // 处理所有在 after 中的文件（新增或修改）
-    for (path, new_hash) in &after_files {
+    for (path, new_node) in &after_files {
+        let preserved = preserve.is_match(path, false);
+        let leaf = if preserved {
+            let mut leaf = new_node.clone();
+            leaf["preserve"] = json!(true);
+            leaf
+        } else {
+            new_node.clone()
+        };
         match before_files.get(path) {
-            Some(old_hash) if old_hash != new_hash => {
+            Some(old_node) if is_content_changed(old_node, new_node) => {
                 // 文件被修改
-                add_to_update_tree(&mut update, path, new_hash);
+                add_to_update_tree(&mut update, path, &leaf);
+                changed_paths.push(path);
+                record(Decision::Modified, path, preserved, || {
+                    content_change(old_node, new_node)
+                });
             }
             None => {
                 // 文件被添加
-                add_to_update_tree(&mut update, path, new_hash);
+                add_to_update_tree(&mut update, path, &leaf);
+                changed_paths.push(path);
+                record(Decision::Added, path, preserved, || {
+                    "not in the previous version".to_string()
+                });
+            }
+            Some(old_node) if preserved => {
+                // 受保护的文件不修改权限
+                record(Decision::Unchanged, path, preserved, || {
+                    if is_mode_changed(old_node, new_node) {
+                        "same content, permission changes are not applied".to_string()
+                    } else {
+                        same_content(old_node, new_node)
+                    }
+                });
             }
-            _ => {
+            Some(old_node) if is_mode_changed(old_node, new_node) => {
+                // 内容未变，仅权限位变化
+                chmod.insert(path.clone(), new_node["mode"].clone());
+                changed_paths.push(path);
+                record(Decision::Modified, path, preserved, || {
+                    format!(
+                        "permissions {} -> {}",
+                        text(&old_node["mode"]),
+                        text(&new_node["mode"])
+                    )
+                });
+            }
+            Some(old_node) => {
                 // 文件未变化，不需要处理
+                record(Decision::Unchanged, path, preserved, || {
+                    same_content(old_node, new_node)
+                });
             }
         }
     }
 
     // 处理被删除的文件
     for path in before_files.keys() {
-        if !after_files.contains_key(path) {
+        if after_files.contains_key(path) {
+            continue;
+        }
+        if preserve.is_match(path, false) {
+            record(Decision::Unchanged, path, true, || {
+                "not in the current version".to_string()
+            });
+        } else {
             deleted.push(path.clone());
+            changed_paths.push(path);
+            record(Decision::Deleted, path, false, || {
+                "not in the current version".to_string()
+            });
         }
     }
 
-    json!({
+    let mut changes = json!({
         "version": "1.0",
         "update": update,
-        "deleted": deleted
-    })
+        "deleted": deleted,
+        "chmod": chmod
+    });
+
+    // 记录有变化的内层压缩包及其格式，应用补丁时需要重新打包
+    let mut archives = nested_archives(before_inner);
+    archives.extend(nested_archives(after_inner));
+    archives.retain(|archive, _| {
+        let prefix = format!("{}/", archive);
+        changed_paths.iter().any(|path| path.starts_with(&prefix))
+    });
+    if !archives.is_empty() {
+        changes["archives"] = json!(archives);
+    }
+    changes
+}
+
+/// 判断迁移记录中是否有需要应用的变化
+///
+/// 文件只在规范化前不同、或只是受保护的文件被删除或修改了权限时，清单的哈希不同
+/// 但迁移记录为空，此时两个版本仍视为相同。
+pub fn has_changes(changes: &Value) -> bool {
+    let non_empty = |key: &str| match changes.get(key) {
+        Some(Value::Object(map)) => !map.is_empty(),
+        Some(Value::Array(list)) => !list.is_empty(),
+        _ => false,
+    };
+    ["update", "deleted", "chmod", "archives"]
+        .into_iter()
+        .any(non_empty)
+}
+
+/// 收集清单中作为目录比较的内层压缩包，返回路径 -> 格式
+pub fn nested_archives(inner: &Value) -> BTreeMap<String, String> {
+    let mut archives = BTreeMap::new();
+    collect_archives(inner, "", &mut archives);
+    archives
+}
+
+fn collect_archives(node: &Value, current_path: &str, archives: &mut BTreeMap<String, String>) {
+    if let Some(format) = node.get("archive").and_then(|v| v.as_str())
+        && !current_path.is_empty()
+    {
+        archives.insert(current_path.to_string(), format.to_string());
+    }
+    for child in node
+        .get("child")
+        .and_then(|v| v.as_array())
+        .into_iter()
+        .flatten()
+    {
+        for (name, child_value) in child.as_object().into_iter().flatten() {
+            let child_path = if current_path.is_empty() {
+                name.clone()
+            } else {
+                format!("{}/{}", current_path, name)
+            };
+            collect_archives(child_value, &child_path, archives);
+        }
+    }
 }
 
 /// 获取更新的文件列表（新增或修改）
@@ -46,9 +169,9 @@ pub fn get_updated_files(before_inner: &Value, after_inner: &Value) -> Vec<Strin
     let after_files = flatten_to_map(after_inner, String::new());
     let mut updated_files = Vec::new();
 
-    for (path, new_hash) in &after_files {
+    for (path, new_node) in &after_files {
         match before_files.get(path) {
-            Some(old_hash) if old_hash != new_hash => {
+            Some(old_node) if is_content_changed(old_node, new_node) => {
                 updated_files.push(path.clone());
             }
             None => {
@@ -60,14 +183,81 @@ pub fn get_updated_files(before_inner: &Value, after_inner: &Value) -> Vec<Strin
     updated_files
 }
 
+/// 判断文件内容是否变化：哈希不同，或符号链接的目标不同
+/// 两侧都带有规范化哈希且相同时，视为仅换行符或空白不同，不算变化
+fn is_content_changed(old_node: &Value, new_node: &Value) -> bool {
+    if let (Some(old_text), Some(new_text)) = (old_node.get("text_hash"), new_node.get("text_hash"))
+        && old_text == new_text
+    {
+        return false;
+    }
+    old_node.get("hash") != new_node.get("hash") || old_node.get("link") != new_node.get("link")
+}
+
+/// 记录对文件的决定，原因只在日志启用时计算
+fn record(decision: Decision, path: &str, preserved: bool, reason: impl FnOnce() -> String) {
+    if !audit::enabled(decision) {
+        return;
+    }
+    let mut reason = reason();
+    if preserved {
+        reason.push_str(match decision {
+            Decision::Unchanged => "; preserved, never deleted or overwritten",
+            _ => "; preserved, installed only where missing",
+        });
+    }
+    audit::record(decision, path, &reason);
+}
+
+/// 说明文件内容如何变化
+fn content_change(old_node: &Value, new_node: &Value) -> String {
+    if old_node.get("link") != new_node.get("link") {
+        format!(
+            "link target {} -> {}",
+            text(&old_node["link"]),
+            text(&new_node["link"])
+        )
+    } else {
+        format!(
+            "hash {} -> {}",
+            text(&old_node["hash"]),
+            text(&new_node["hash"])
+        )
+    }
+}
+
+/// 说明内容为何视为相同
+fn same_content(old_node: &Value, new_node: &Value) -> String {
+    if old_node.get("hash") != new_node.get("hash") {
+        "same content after text normalisation".to_string()
+    } else if text(&old_node["hash"]).starts_with("crc32:") {
+        "same CRC32 and size".to_string()
+    } else {
+        "same hash".to_string()
+    }
+}
+
+/// 日志中显示的取值，缺失时为 `none`
+fn text(value: &Value) -> String {
+    value.as_str().unwrap_or("none").to_string()
+}
+
+/// 判断权限位是否变化，只有两侧都记录了权限时才比较
+fn is_mode_changed(old_node: &Value, new_node: &Value) -> bool {
+    match (old_node.get("mode"), new_node.get("mode")) {
+        (Some(old_mode), Some(new_mode)) => old_mode != new_mode,
+        _ => false,
+    }
+}
+
 /// 将路径添加到更新树中
-fn add_to_update_tree(tree: &mut Value, path: &str, hash: &str) {
+fn add_to_update_tree(tree: &mut Value, path: &str, leaf: &Value) {
     let parts: Vec<&str> = path.split('/').collect();
-    add_to_tree_recursive(tree, &parts, hash, 0);
+    add_to_tree_recursive(tree, &parts, leaf, 0);
 }
 
 /// 递归辅助函数，用于添加路径到更新树
-fn add_to_tree_recursive(node: &mut Value, parts: &[&str], hash: &str, index: usize) {
+fn add_to_tree_recursive(node: &mut Value, parts: &[&str], leaf: &Value, index: usize) {
     if index >= parts.len() {
         return;
     }
@@ -82,12 +272,7 @@ fn add_to_tree_recursive(node: &mut Value, parts: &[&str], hash: &str, index: us
     if index == parts.len() - 1 {
         // 最后一个部分，插入文件信息
         if let Some(obj) = node.as_object_mut() {
-            obj.insert(
-                part.to_string(),
-                json!({
-                    "hash": hash
-                }),
-            );
+            obj.insert(part.to_string(), leaf.clone());
         }
     } else {
         // 中间路径，递归处理
@@ -101,15 +286,15 @@ fn add_to_tree_recursive(node: &mut Value, parts: &[&str], hash: &str, index: us
 
             // 递归处理下一级
             if let Some(child) = obj.get_mut(&part_string) {
-                add_to_tree_recursive(child, parts, hash, index + 1);
+                add_to_tree_recursive(child, parts, leaf, index + 1);
             }
         }
     }
 }
 
-/// 将嵌套的 JSON 结构展平为路径 -> 哈希的映射
-/// 只返回文件节点（叶子节点），不返回目录节点
-fn flatten_to_map(value: &Value, current_path: String) -> HashMap<String, String> {
+/// 将嵌套的 JSON 结构展平为路径 -> 叶子节点的映射
+/// 只返回文件节点（叶子节点，包括符号链接），不返回目录节点
+fn flatten_to_map(value: &Value, current_path: String) -> HashMap<String, Value> {
     let mut result = HashMap::new();
 
     if let Some(obj) = value.as_object() {
@@ -117,10 +302,9 @@ fn flatten_to_map(value: &Value, current_path: String) -> HashMap<String, String
         let has_children = obj.get("child").is_some();
 
         // 只有当节点有 hash 但没有 child 时，才认为它是文件
-        if has_hash && !has_children && !current_path.is_empty()
-            && let Some(hash) = obj.get("hash").and_then(|v| v.as_str()) {
-                result.insert(current_path.clone(), hash.to_string());
-            }
+        if has_hash && !has_children && !current_path.is_empty() {
+            result.insert(current_path.clone(), value.clone());
+        }
 
         // 递归处理子节点
         if let Some(children) = obj.get("child").and_then(|v| v.as_array()) {
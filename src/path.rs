@@ -103,3 +103,30 @@ pub fn is_safe_path(path: &str) -> bool {
 
     true
 }
+
+/// 检查尚未创建的输出文件路径是否安全
+///
+/// `is_safe_path` 要求路径已经存在，而补丁文件在写出前并不存在。
+/// 此时改为检查其父目录：父目录经符号链接指向当前目录之外时同样拒绝。
+/// 路径本身已存在时（包括指向别处的悬空符号链接）仍交给 `is_safe_path` 检查。
+pub fn is_safe_output_path(path: &str) -> bool {
+    let path_buf = PathBuf::from(path);
+    match fs::symlink_metadata(&path_buf) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        _ => return is_safe_path(path),
+    }
+
+    // 与 is_safe_path 使用相同的字符黑名单，父目录的检查不会覆盖文件名
+    let forbidden_patterns = ["..", "~", "%", "$", "{", "}", "<", ">", "|", "\""];
+    if forbidden_patterns.iter().any(|pattern| path.contains(pattern)) {
+        return false;
+    }
+    if path_buf.file_name().is_none() {
+        return false;
+    }
+
+    match path_buf.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => is_safe_path(&parent.to_string_lossy()),
+        _ => is_safe_path("."),
+    }
+}
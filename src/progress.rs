@@ -0,0 +1,189 @@
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+/// Stage of a run that progress is reported for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// Unpacking an archive to disk.
+    Extract,
+    /// Hashing the entries of a directory or archive.
+    Hash,
+    /// Writing the patch archive.
+    Compress,
+}
+
+impl Phase {
+    pub fn name(self) -> &'static str {
+        match self {
+            Phase::Extract => "extract",
+            Phase::Hash => "hash",
+            Phase::Compress => "compress",
+        }
+    }
+}
+
+/// Receives progress updates, for example to drive a progress bar.
+///
+/// Every method has an empty default, so implementations only override what they show.
+/// Phases may nest: an archive inside a hashed directory or archive is hashed in a
+/// phase of its own, started and finished within the outer one.
+pub trait ProgressReporter: Send + Sync {
+    /// A phase starts on the input or output `name`.
+    ///
+    /// `total` is the number of bytes the phase is expected to process, when known.
+    fn start(&self, _phase: Phase, _name: &str, _total: Option<u64>) {}
+
+    /// The phase reached a new entry.
+    fn entry(&self, _phase: Phase, _name: &str) {}
+
+    /// The phase processed `bytes` more bytes of uncompressed data.
+    fn advance(&self, _phase: Phase, _bytes: u64) {}
+
+    /// The phase started last has ended, successfully or not.
+    fn finish(&self, _phase: Phase) {}
+}
+
+static REPORTER: RwLock<Option<Arc<dyn ProgressReporter>>> = RwLock::new(None);
+
+/// Sends the progress of every archive operation of this process to `reporter`.
+pub fn set_reporter(reporter: Arc<dyn ProgressReporter>) {
+    *REPORTER.write().unwrap_or_else(|e| e.into_inner()) = Some(reporter);
+}
+
+/// Stops reporting progress.
+pub fn clear_reporter() {
+    *REPORTER.write().unwrap_or_else(|e| e.into_inner()) = None;
+}
+
+/// Returns the reporter installed with [`set_reporter`], if any.
+pub fn reporter() -> Option<Arc<dyn ProgressReporter>> {
+    REPORTER.read().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// Reports the entries and bytes of one phase to the installed reporter.
+///
+/// Does nothing when no reporter is installed.
+pub struct PhaseProgress {
+    reporter: Option<Arc<dyn ProgressReporter>>,
+    phase: Phase,
+    owns_phase: bool,
+}
+
+impl PhaseProgress {
+    /// Starts a phase, which finishes when the returned value is dropped.
+    ///
+    /// `total` is only evaluated when a reporter is installed.
+    pub fn start(phase: Phase, name: &str, total: impl FnOnce() -> Option<u64>) -> Self {
+        let reporter = reporter();
+        if let Some(reporter) = &reporter {
+            reporter.start(phase, name, total());
+        }
+        PhaseProgress {
+            reporter,
+            phase,
+            owns_phase: true,
+        }
+    }
+
+    /// Reports into a phase that the caller has already started, such as a writer
+    /// running inside [`compress`](crate::compress::compress).
+    pub fn within(phase: Phase) -> Self {
+        PhaseProgress {
+            reporter: reporter(),
+            phase,
+            owns_phase: false,
+        }
+    }
+
+    pub fn entry(&self, name: &str) {
+        if let Some(reporter) = &self.reporter {
+            reporter.entry(self.phase, name);
+        }
+    }
+
+    pub fn advance(&self, bytes: u64) {
+        if let Some(reporter) = &self.reporter
+            && bytes > 0
+        {
+            reporter.advance(self.phase, bytes);
+        }
+    }
+
+    /// Wraps a reader so that every byte read through it is reported.
+    pub fn reader<'p, R: Read + ?Sized>(&'p self, inner: &'p mut R) -> ProgressReader<'p, R> {
+        ProgressReader {
+            inner,
+            progress: self,
+        }
+    }
+
+    /// Wraps a writer so that every byte written through it is reported.
+    pub fn writer<W: Write>(&self, inner: W) -> ProgressWriter<'_, W> {
+        ProgressWriter {
+            inner,
+            progress: self,
+        }
+    }
+}
+
+impl Drop for PhaseProgress {
+    fn drop(&mut self) {
+        if let Some(reporter) = &self.reporter
+            && self.owns_phase
+        {
+            reporter.finish(self.phase);
+        }
+    }
+}
+
+/// Reader returned by [`PhaseProgress::reader`].
+pub struct ProgressReader<'p, R: Read + ?Sized> {
+    inner: &'p mut R,
+    progress: &'p PhaseProgress,
+}
+
+impl<R: Read + ?Sized> Read for ProgressReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.progress.advance(read as u64);
+        Ok(read)
+    }
+}
+
+/// Writer returned by [`PhaseProgress::writer`].
+pub struct ProgressWriter<'p, W: Write> {
+    inner: W,
+    progress: &'p PhaseProgress,
+}
+
+impl<W: Write> ProgressWriter<'_, W> {
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for ProgressWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.progress.advance(written as u64);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Total size of the regular files below `path`, used as the expected size of a phase.
+///
+/// Symbolic links are not followed and unreadable entries are skipped.
+pub fn directory_size(path: &Path) -> u64 {
+    walkdir::WalkDir::new(path)
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_type().is_file())
+        .filter_map(|entry| entry.metadata().ok())
+        .map(|metadata| metadata.len())
+        .sum()
+}
@@ -0,0 +1,92 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+use indicatif::{ProgressBar, ProgressStyle};
+use pulonia::progress::{Phase, ProgressReporter};
+
+/// 在终端中以进度条显示各阶段的进度
+///
+/// 已知总大小时显示进度条和剩余时间，否则显示已处理的字节数和速度。
+/// 嵌套的阶段（如目录中的内层压缩包）计入外层阶段的进度条。
+#[derive(Default)]
+pub struct TerminalProgress {
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    bar: Option<ProgressBar>,
+    depth: usize,
+    entries: u64,
+}
+
+impl TerminalProgress {
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn verb(phase: Phase) -> &'static str {
+    match phase {
+        Phase::Extract => "Extracting",
+        Phase::Hash => "Hashing",
+        Phase::Compress => "Compressing",
+    }
+}
+
+impl ProgressReporter for TerminalProgress {
+    fn start(&self, phase: Phase, name: &str, total: Option<u64>) {
+        let mut state = self.state();
+        state.depth += 1;
+        if state.depth > 1 {
+            return;
+        }
+        let bar = match total {
+            Some(total) => ProgressBar::new(total).with_style(
+                ProgressStyle::with_template(
+                    "{prefix} [{bar:30}] {bytes}/{total_bytes} {msg} ({bytes_per_sec}, ETA {eta})",
+                )
+                .expect("valid progress template")
+                .progress_chars("=> "),
+            ),
+            None => ProgressBar::new_spinner().with_style(
+                ProgressStyle::with_template(
+                    "{spinner} {prefix} {bytes} {msg} ({bytes_per_sec}, {elapsed})",
+                )
+                .expect("valid progress template"),
+            ),
+        };
+        bar.set_prefix(format!("{} {}", verb(phase), name));
+        bar.enable_steady_tick(Duration::from_millis(200));
+        state.bar = Some(bar);
+        state.entries = 0;
+    }
+
+    fn entry(&self, _phase: Phase, _name: &str) {
+        let mut state = self.state();
+        state.entries += 1;
+        let entries = state.entries;
+        if let Some(bar) = &state.bar {
+            bar.set_message(format!("{} entries", entries));
+        }
+    }
+
+    fn advance(&self, _phase: Phase, bytes: u64) {
+        if let Some(bar) = &self.state().bar {
+            bar.inc(bytes);
+        }
+    }
+
+    fn finish(&self, _phase: Phase) {
+        let mut state = self.state();
+        state.depth = state.depth.saturating_sub(1);
+        if state.depth > 0 {
+            return;
+        }
+        if let Some(bar) = state.bar.take() {
+            // 预估的总大小可能偏大，结束时以实际处理的字节数为准
+            bar.set_length(bar.position());
+            bar.finish();
+        }
+    }
+}
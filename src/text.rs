@@ -0,0 +1,105 @@
+use globset::{Glob, GlobMatcher};
+use sha2::{Digest, Sha256};
+use std::io::{BufRead, BufReader, Read};
+
+/// 按 glob 选中的文本文件在比较前进行的规范化
+///
+/// 规则写法为 `<glob>[=<选项>]`，选项以逗号分隔，可取 `eol`（统一换行符）、
+/// `trailing-whitespace`（去除行尾空白）和 `bom`（去除 UTF-8 BOM），省略时全部启用。
+#[derive(Debug, Clone)]
+pub struct TextRule {
+    matcher: GlobMatcher,
+    eol: bool,
+    trailing_whitespace: bool,
+    bom: bool,
+}
+
+const UTF8_BOM: &[u8] = &[0xEF, 0xBB, 0xBF];
+
+impl TextRule {
+    pub fn parse(rule: &str) -> Result<Self, String> {
+        let (pattern, options) = match rule.split_once('=') {
+            Some((pattern, options)) => (pattern, Some(options)),
+            None => (rule, None),
+        };
+        let matcher = Glob::new(pattern)
+            .map_err(|e| format!("Invalid glob '{}': {}", pattern, e))?
+            .compile_matcher();
+
+        let mut text_rule = TextRule {
+            matcher,
+            eol: options.is_none(),
+            trailing_whitespace: options.is_none(),
+            bom: options.is_none(),
+        };
+        for option in options.unwrap_or_default().split(',') {
+            match option.trim() {
+                "eol" => text_rule.eol = true,
+                "trailing-whitespace" => text_rule.trailing_whitespace = true,
+                "bom" => text_rule.bom = true,
+                "" => {}
+                other => return Err(format!("Unknown normalisation option '{}'", other)),
+            }
+        }
+        Ok(text_rule)
+    }
+
+    pub fn is_match(&self, path: &str) -> bool {
+        self.matcher.is_match(path)
+    }
+
+    /// 返回规范化后的行内容以及应使用的换行符
+    fn normalize_line<'a>(&self, mut line: &'a [u8], first: bool) -> (&'a [u8], &'static [u8]) {
+        if first && self.bom && line.starts_with(UTF8_BOM) {
+            line = &line[UTF8_BOM.len()..];
+        }
+        let ending: &'static [u8] = if line.ends_with(b"\r\n") {
+            line = &line[..line.len() - 2];
+            if self.eol { b"\n" } else { b"\r\n" }
+        } else if line.ends_with(b"\n") {
+            line = &line[..line.len() - 1];
+            b"\n"
+        } else {
+            b""
+        };
+        if self.trailing_whitespace {
+            while let [rest @ .., b' ' | b'\t'] = line {
+                line = rest;
+            }
+        }
+        (line, ending)
+    }
+}
+
+/// 选出第一条匹配路径的规则
+pub fn find_rule<'a>(rules: &'a [TextRule], path: &str) -> Option<&'a TextRule> {
+    rules.iter().find(|rule| rule.is_match(path))
+}
+
+/// 一次读取同时计算原始哈希与规范化后的哈希
+pub fn hash_text<R: Read + ?Sized>(
+    reader: &mut R,
+    rule: &TextRule,
+) -> std::io::Result<(String, String)> {
+    let mut reader = BufReader::new(reader);
+    let mut exact = Sha256::new();
+    let mut normalized = Sha256::new();
+    let mut line = Vec::new();
+    let mut first = true;
+    loop {
+        line.clear();
+        let bytes_read = reader.read_until(b'\n', &mut line)?;
+        if bytes_read == 0 {
+            break;
+        }
+        exact.update(&line);
+        let (content, ending) = rule.normalize_line(&line, first);
+        normalized.update(content);
+        normalized.update(ending);
+        first = false;
+    }
+    Ok((
+        format!("{:x}", exact.finalize()),
+        format!("{:x}", normalized.finalize()),
+    ))
+}
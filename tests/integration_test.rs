@@ -116,8 +116,1914 @@ fn test_generate_migration_report() -> Result<(), Box<dyn std::error::Error>> {
     // Clean up migration file
     fs::remove_file(migration_file)?;
 
+    // Clean up generated patch
+    fs::remove_file(current_dir.join("ota.zip"))?;
+
     // Clean up test temp directory
     fs::remove_dir_all(&test_temp_dir)?;
 
     Ok(())
 }
+
+/// Creates an empty working directory for a single test inside the crate root.
+///
+/// pulonia only accepts paths below its working directory, and writes the
+/// migration report there, so every test runs the binary from its own directory.
+fn create_workspace(name: &str) -> std::io::Result<std::path::PathBuf> {
+    let dir = std::env::current_dir()?.join(format!(".test_temp_{}", name));
+    if dir.exists() {
+        fs::remove_dir_all(&dir)?;
+    }
+    fs::create_dir(&dir)?;
+    Ok(dir)
+}
+
+fn pulonia_in(dir: &Path) -> assert_cmd::Command {
+    let cmd = std::process::Command::new(assert_cmd::cargo::cargo_bin!("pulonia"));
+    let mut cmd = assert_cmd::Command::from_std(cmd);
+    cmd.current_dir(dir);
+    cmd
+}
+
+/// Reads the migration record of the last run and removes it.
+///
+/// Record names only have minute resolution, so a record left by an earlier run in the
+/// same workspace could otherwise be read instead; finding several is an error.
+fn read_migration(dir: &Path) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+    let mut records = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.starts_with("migration_") && name.ends_with(".json") {
+            records.push(entry.path());
+        }
+    }
+    match records.as_slice() {
+        [] => Err("Migration file not found".into()),
+        [record] => {
+            let content = fs::read_to_string(record)?;
+            fs::remove_file(record)?;
+            Ok(serde_json::from_str(&content)?)
+        }
+        _ => Err(format!("Several migration files found: {:?}", records).into()),
+    }
+}
+
+fn zip_entry_names(zip_file: &Path) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let mut archive = zip::ZipArchive::new(File::open(zip_file)?)?;
+    let mut names = Vec::new();
+    for i in 0..archive.len() {
+        let entry = archive.by_index(i)?;
+        if !entry.is_dir() {
+            names.push(entry.name().to_string());
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+#[test]
+fn test_stream_mode_only_packs_changed_files() -> Result<(), Box<dyn std::error::Error>> {
+    let root = create_workspace("stream")?;
+
+    let before_dir = root.join("before");
+    fs::create_dir_all(before_dir.join("lib"))?;
+    fs::write(before_dir.join("file1.txt"), "content A")?;
+    fs::write(before_dir.join("lib/file2.txt"), "content B")?;
+    fs::write(before_dir.join("old.txt"), "content E")?;
+
+    let after_dir = root.join("after");
+    fs::create_dir_all(after_dir.join("lib"))?;
+    fs::write(after_dir.join("file1.txt"), "content A")?;
+    fs::write(after_dir.join("lib/file2.txt"), "content C")?;
+    fs::write(after_dir.join("lib/file3.txt"), "content D")?;
+
+    create_zip(&before_dir, &root.join("before.zip"))?;
+    create_zip(&after_dir, &root.join("after.zip"))?;
+
+    pulonia_in(&root)
+        .args([
+            "--before",
+            "before.zip",
+            "--after",
+            "after.zip",
+            "--output",
+            "patch.zip",
+        ])
+        .arg("--stream")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Patch file created successfully"));
+
+    let json = read_migration(&root)?;
+    assert!(json["update"]["lib"].get("file2.txt").is_some());
+    assert!(json["update"]["lib"].get("file3.txt").is_some());
+    assert!(json["update"].get("file1.txt").is_none());
+    assert_eq!(json["deleted"], serde_json::json!(["old.txt"]));
+
+    assert_eq!(
+        zip_entry_names(&root.join("patch.zip"))?,
+        vec!["lib/file2.txt", "lib/file3.txt"]
+    );
+
+    fs::remove_dir_all(&root)?;
+    Ok(())
+}
+
+#[test]
+fn test_stream_mode_extracts_hard_link_targets() -> Result<(), Box<dyn std::error::Error>> {
+    let root = create_workspace("stream_hard_link")?;
+
+    let write_tar = |path: &Path, with_link: bool| -> std::io::Result<()> {
+        let mut builder = tar::Builder::new(File::create(path)?);
+        let mut header = tar::Header::new_gnu();
+        header.set_size(9);
+        header.set_mode(0o644);
+        header.set_entry_type(tar::EntryType::Regular);
+        builder.append_data(&mut header, "data.txt", &b"content A"[..])?;
+        if with_link {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(0);
+            header.set_mode(0o644);
+            header.set_entry_type(tar::EntryType::Link);
+            builder.append_link(&mut header, "link.txt", "data.txt")?;
+        }
+        builder.finish()
+    };
+    write_tar(&root.join("before.tar"), false)?;
+    write_tar(&root.join("after.tar"), true)?;
+
+    // The new link points at an unchanged file that is not extracted itself
+    pulonia_in(&root)
+        .args(["-b", "before.tar", "-a", "after.tar", "-o", "patch.zip"])
+        .arg("--stream")
+        .assert()
+        .success();
+
+    let json = read_migration(&root)?;
+    assert!(json["update"].get("link.txt").is_some());
+    assert!(json["update"].get("data.txt").is_none());
+    let mut patch = zip::ZipArchive::new(File::open(root.join("patch.zip"))?)?;
+    let mut content = String::new();
+    std::io::Read::read_to_string(&mut patch.by_name("link.txt")?, &mut content)?;
+    assert_eq!(content, "content A");
+    assert!(patch.by_name("data.txt").is_err());
+
+    fs::remove_dir_all(&root)?;
+    Ok(())
+}
+
+#[test]
+fn test_zip_prefilter_detects_changes() -> Result<(), Box<dyn std::error::Error>> {
+    let root = create_workspace("zip_prefilter")?;
+
+    let before_dir = root.join("before");
+    fs::create_dir(&before_dir)?;
+    fs::write(before_dir.join("same.txt"), "content A")?;
+    fs::write(before_dir.join("changed.txt"), "content B")?;
+    fs::write(before_dir.join("removed.txt"), "content E")?;
+
+    let after_dir = root.join("after");
+    fs::create_dir(&after_dir)?;
+    fs::write(after_dir.join("same.txt"), "content A")?;
+    fs::write(after_dir.join("changed.txt"), "content C")?;
+    fs::write(after_dir.join("added.txt"), "content D")?;
+
+    create_zip(&before_dir, &root.join("before.zip"))?;
+    create_zip(&after_dir, &root.join("after.zip"))?;
+
+    pulonia_in(&root)
+        .args(["-b", "before.zip", "-a", "after.zip", "-o", "patch.zip"])
+        .args(["--zip-prefilter", "-vv"])
+        .assert()
+        .success()
+        // same.txt is never decompressed; only changed.txt and added.txt are hashed
+        .stderr(predicate::str::contains(
+            "Zip pre-filter: 1 of 3 entries matched by CRC32 and size, 2 hashed",
+        ))
+        .stderr(predicate::str::contains(
+            "unchanged same.txt: same CRC32 and size",
+        ));
+
+    let json = read_migration(&root)?;
+    assert!(json["update"].get("changed.txt").is_some());
+    assert!(json["update"].get("added.txt").is_some());
+    assert!(json["update"].get("same.txt").is_none());
+    assert_eq!(json["deleted"], serde_json::json!(["removed.txt"]));
+    assert_eq!(
+        zip_entry_names(&root.join("patch.zip"))?,
+        vec!["added.txt", "changed.txt"]
+    );
+
+    // Names that only match once the top-level folders are aligned or stripped
+    for (before_name, after_name, strip) in [
+        ("app-1.0", "app-1.1", None),
+        ("server", "client", Some("1")),
+    ] {
+        let before_dir = root.join("before_wrapped");
+        let after_dir = root.join("after_wrapped");
+        for dir in [&before_dir, &after_dir] {
+            if dir.exists() {
+                fs::remove_dir_all(dir)?;
+            }
+        }
+        fs::create_dir_all(before_dir.join(before_name))?;
+        fs::write(before_dir.join(before_name).join("x.txt"), "content X")?;
+        fs::write(before_dir.join(before_name).join("y.txt"), "content Y")?;
+        fs::create_dir_all(after_dir.join(after_name))?;
+        fs::write(after_dir.join(after_name).join("x.txt"), "content X")?;
+        fs::write(after_dir.join(after_name).join("y.txt"), "content Z")?;
+        create_zip(&before_dir, &root.join("before_wrapped.zip"))?;
+        create_zip(&after_dir, &root.join("after_wrapped.zip"))?;
+
+        let mut cmd = pulonia_in(&root);
+        cmd.args(["-b", "before_wrapped.zip", "-a", "after_wrapped.zip"])
+            .args(["-o", "wrapped.zip", "--zip-prefilter"]);
+        if let Some(count) = strip {
+            cmd.args(["--strip-components", count]);
+        }
+        cmd.assert().success();
+
+        let json = read_migration(&root)?;
+        assert!(json["update"].get("y.txt").is_some(), "{}", json);
+        assert!(json["update"].get("x.txt").is_none(), "{}", json);
+        assert_eq!(json["deleted"], serde_json::json!([]));
+        assert_eq!(zip_entry_names(&root.join("wrapped.zip"))?, vec!["y.txt"]);
+    }
+
+    fs::remove_dir_all(&root)?;
+    Ok(())
+}
+
+fn create_tar(src_dir: &Path, dst_file: &Path) -> std::io::Result<()> {
+    let mut builder = tar::Builder::new(File::create(dst_file)?);
+    builder.follow_symlinks(false);
+    builder.append_dir_all(".", src_dir)?;
+    builder.finish()
+}
+
+#[cfg(unix)]
+#[test]
+fn test_symlinks_are_diffed_and_packed_as_links() -> Result<(), Box<dyn std::error::Error>> {
+    use std::os::unix::fs::symlink;
+
+    let root = create_workspace("symlink")?;
+
+    let before_dir = root.join("before");
+    fs::create_dir(&before_dir)?;
+    fs::write(before_dir.join("libfoo.so.1"), "v1")?;
+    symlink("libfoo.so.1", before_dir.join("libfoo.so"))?;
+    symlink("missing.txt", before_dir.join("dangling"))?;
+
+    let after_dir = root.join("after");
+    fs::create_dir(&after_dir)?;
+    fs::write(after_dir.join("libfoo.so.1"), "v1")?;
+    fs::write(after_dir.join("libfoo.so.2"), "v2")?;
+    symlink("libfoo.so.2", after_dir.join("libfoo.so"))?;
+    symlink("missing.txt", after_dir.join("dangling"))?;
+
+    create_tar(&before_dir, &root.join("before.tar"))?;
+    create_tar(&after_dir, &root.join("after.tar"))?;
+
+    pulonia_in(&root)
+        .args(["-b", "before.tar", "-a", "after.tar", "-o", "patch.tar"])
+        .assert()
+        .success();
+
+    let json = read_migration(&root)?;
+    assert_eq!(json["update"]["libfoo.so"]["link"], "libfoo.so.2");
+    assert!(json["update"].get("libfoo.so.2").is_some());
+    assert!(json["update"].get("dangling").is_none());
+    assert!(json["deleted"].as_array().unwrap().is_empty());
+
+    let mut archive = tar::Archive::new(File::open(root.join("patch.tar"))?);
+    let mut link_target = None;
+    for entry in archive.entries()? {
+        let entry = entry?;
+        if entry.path()?.ends_with("libfoo.so") {
+            assert!(entry.header().entry_type().is_symlink());
+            link_target = entry.link_name()?.map(|p| p.to_string_lossy().to_string());
+        }
+    }
+    assert_eq!(link_target.as_deref(), Some("libfoo.so.2"));
+
+    fs::remove_dir_all(&root)?;
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn test_permission_changes_are_recorded_and_kept() -> Result<(), Box<dyn std::error::Error>> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let root = create_workspace("permissions")?;
+
+    let before_dir = root.join("before");
+    fs::create_dir(&before_dir)?;
+    fs::write(before_dir.join("run.sh"), "#!/bin/sh\n")?;
+    fs::set_permissions(before_dir.join("run.sh"), fs::Permissions::from_mode(0o644))?;
+    fs::write(before_dir.join("data.bin"), "old")?;
+
+    let after_dir = root.join("after");
+    fs::create_dir(&after_dir)?;
+    fs::write(after_dir.join("run.sh"), "#!/bin/sh\n")?;
+    fs::set_permissions(after_dir.join("run.sh"), fs::Permissions::from_mode(0o755))?;
+    fs::write(after_dir.join("data.bin"), "new")?;
+    fs::set_permissions(
+        after_dir.join("data.bin"),
+        fs::Permissions::from_mode(0o640),
+    )?;
+
+    create_tar(&before_dir, &root.join("before.tar"))?;
+    create_tar(&after_dir, &root.join("after.tar"))?;
+
+    pulonia_in(&root)
+        .args(["-b", "before.tar", "-a", "after.tar", "-o", "patch.zip"])
+        .assert()
+        .success();
+
+    let json = read_migration(&root)?;
+    assert_eq!(json["chmod"], serde_json::json!({ "run.sh": "0755" }));
+    assert!(json["update"].get("run.sh").is_none());
+    assert_eq!(json["update"]["data.bin"]["mode"], "0640");
+
+    let mut archive = zip::ZipArchive::new(File::open(root.join("patch.zip"))?)?;
+    let entry = archive.by_name("data.bin")?;
+    assert_eq!(entry.unix_mode().map(|m| m & 0o777), Some(0o640));
+
+    fs::remove_dir_all(&root)?;
+    Ok(())
+}
+
+#[test]
+fn test_normalize_ignores_line_ending_changes() -> Result<(), Box<dyn std::error::Error>> {
+    let root = create_workspace("normalize")?;
+
+    let before_dir = root.join("before");
+    fs::create_dir(&before_dir)?;
+    fs::write(
+        before_dir.join("config.json"),
+        "\u{feff}{\r\n  \"a\": 1\r\n}\r\n",
+    )?;
+    fs::write(before_dir.join("run.sh"), "echo hi  \r\n")?;
+    fs::write(before_dir.join("notes.md"), "line\r\n")?;
+
+    let after_dir = root.join("after");
+    fs::create_dir(&after_dir)?;
+    fs::write(after_dir.join("config.json"), "{\n  \"a\": 1\n}\n")?;
+    fs::write(after_dir.join("run.sh"), "echo hi\n")?;
+    fs::write(after_dir.join("notes.md"), "line\n")?;
+
+    create_zip(&before_dir, &root.join("before.zip"))?;
+    create_zip(&after_dir, &root.join("after.zip"))?;
+
+    pulonia_in(&root)
+        .args(["-b", "before.zip", "-a", "after.zip", "-o", "patch.zip"])
+        .args([
+            "--normalize",
+            "*.json",
+            "--normalize",
+            "*.sh=eol,trailing-whitespace",
+        ])
+        .assert()
+        .success();
+
+    let json = read_migration(&root)?;
+    assert!(json["update"].get("config.json").is_none());
+    assert!(json["update"].get("run.sh").is_none());
+    assert!(json["update"].get("notes.md").is_some());
+    assert_eq!(zip_entry_names(&root.join("patch.zip"))?, vec!["notes.md"]);
+
+    // Versions that only differ before normalisation are identical
+    pulonia_in(&root)
+        .args(["-b", "before.zip", "-a", "after.zip", "-o", "same.zip"])
+        .args(["--normalize", "*"])
+        .assert()
+        .code(1)
+        .stdout(predicate::str::contains("The two files are identical."))
+        .stdout(predicate::str::contains("Migration report saved").not());
+    assert!(!root.join("same.zip").exists());
+
+    fs::remove_dir_all(&root)?;
+    Ok(())
+}
+
+fn create_tar_zst(src_dir: &Path, dst_file: &Path) -> std::io::Result<()> {
+    let encoder = zstd::stream::write::Encoder::new(File::create(dst_file)?, 3)?;
+    let mut builder = tar::Builder::new(encoder);
+    builder.append_dir_all(".", src_dir)?;
+    builder.into_inner()?.finish()?;
+    Ok(())
+}
+
+#[test]
+fn test_zstd_input_and_patch_output() -> Result<(), Box<dyn std::error::Error>> {
+    let root = create_workspace("zstd")?;
+
+    let before_dir = root.join("before");
+    fs::create_dir(&before_dir)?;
+    fs::write(before_dir.join("file1.txt"), "content A")?;
+
+    let after_dir = root.join("after");
+    fs::create_dir(&after_dir)?;
+    fs::write(after_dir.join("file1.txt"), "content B")?;
+
+    create_tar_zst(&before_dir, &root.join("before.tzst"))?;
+    create_tar_zst(&after_dir, &root.join("after.tar.zst"))?;
+
+    pulonia_in(&root)
+        .args([
+            "-b",
+            "before.tzst",
+            "-a",
+            "after.tar.zst",
+            "-o",
+            "patch.tar.zst",
+        ])
+        .args(["--level", "19", "--long"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Patch file created successfully"));
+
+    let mut decoder = zstd::stream::read::Decoder::new(File::open(root.join("patch.tar.zst"))?)?;
+    decoder.window_log_max(31)?;
+    let mut archive = tar::Archive::new(decoder);
+    let mut names = Vec::new();
+    for entry in archive.entries()? {
+        let entry = entry?;
+        if entry.header().entry_type().is_file() {
+            names.push(entry.path()?.to_string_lossy().to_string());
+        }
+    }
+    assert_eq!(names, vec!["file1.txt"]);
+
+    pulonia_in(&root)
+        .args([
+            "-b",
+            "before.tzst",
+            "-a",
+            "after.tar.zst",
+            "-o",
+            "bad.tar.zst",
+        ])
+        .args(["--level", "99"])
+        .assert()
+        .stderr(predicate::str::contains("Invalid compression option"));
+
+    fs::remove_dir_all(&root)?;
+    Ok(())
+}
+
+#[test]
+fn test_format_detected_from_magic_bytes() -> Result<(), Box<dyn std::error::Error>> {
+    let root = create_workspace("magic")?;
+
+    let before_dir = root.join("before");
+    fs::create_dir(&before_dir)?;
+    fs::write(before_dir.join("file1.txt"), "content A")?;
+
+    let after_dir = root.join("after");
+    fs::create_dir(&after_dir)?;
+    fs::write(after_dir.join("file1.txt"), "content B")?;
+
+    create_zip(&before_dir, &root.join("before.pkg"))?;
+    let encoder = flate2::write::GzEncoder::new(
+        File::create(root.join("download"))?,
+        flate2::Compression::default(),
+    );
+    let mut builder = tar::Builder::new(encoder);
+    builder.append_dir_all(".", &after_dir)?;
+    builder.into_inner()?.finish()?;
+
+    pulonia_in(&root)
+        .args(["-b", "before.pkg", "-a", "download", "-o", "patch.zip"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("before path: before.pkg (zip)"))
+        .stdout(predicate::str::contains("after path: download (tar.gz)"));
+
+    let json = read_migration(&root)?;
+    assert!(json["update"].get("file1.txt").is_some());
+
+    pulonia_in(&root)
+        .args(["-b", "before.pkg", "-a", "download", "-o", "patch.zip"])
+        .args(["--after-format", "rar"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "Unsupported compression format: rar",
+        ));
+
+    fs::remove_dir_all(&root)?;
+    Ok(())
+}
+
+#[test]
+fn test_directories_as_inputs() -> Result<(), Box<dyn std::error::Error>> {
+    let root = create_workspace("directories")?;
+
+    let before_dir = root.join("before");
+    fs::create_dir_all(before_dir.join("sub"))?;
+    fs::write(before_dir.join("file1.txt"), "content A")?;
+    fs::write(before_dir.join("sub/file2.txt"), "content B")?;
+
+    let after_dir = root.join("after");
+    fs::create_dir_all(after_dir.join("sub"))?;
+    fs::write(after_dir.join("file1.txt"), "content A")?;
+    fs::write(after_dir.join("sub/file2.txt"), "content C")?;
+
+    create_tar(&before_dir, &root.join("before.tar"))?;
+
+    // A directory and a streamed archive with the same content are identical.
+    pulonia_in(&root)
+        .args(["-b", "before.tar", "-a", "before", "--stream"])
+        .assert()
+        .code(1)
+        .stdout(predicate::str::contains("The two files are identical."));
+
+    pulonia_in(&root)
+        .args(["-b", "before.tar", "-a", "after", "-o", "patch.zip"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("after path: after (dir)"));
+
+    let json = read_migration(&root)?;
+    assert!(json["update"]["sub"].get("file2.txt").is_some());
+    assert!(json["update"].get("file1.txt").is_none());
+    assert_eq!(
+        zip_entry_names(&root.join("patch.zip"))?,
+        vec!["sub/file2.txt"]
+    );
+
+    fs::remove_dir_all(&root)?;
+    Ok(())
+}
+
+#[test]
+fn test_hostile_archives_are_rejected() -> Result<(), Box<dyn std::error::Error>> {
+    let root = create_workspace("limits")?;
+
+    let before_dir = root.join("before");
+    fs::create_dir(&before_dir)?;
+    fs::write(before_dir.join("file1.txt"), "content A")?;
+    fs::write(before_dir.join("file2.txt"), "content B")?;
+    create_zip(&before_dir, &root.join("before.zip"))?;
+
+    let options = FileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+    let mut zip = zip::ZipWriter::new(File::create(root.join("traversal.zip"))?);
+    zip.start_file("../evil.txt", options)?;
+    std::io::Write::write_all(&mut zip, b"evil")?;
+    zip.finish()?;
+
+    let mut zip = zip::ZipWriter::new(File::create(root.join("bomb.zip"))?);
+    zip.start_file("zeros.bin", options)?;
+    std::io::Write::write_all(&mut zip, &vec![0u8; 4 << 20])?;
+    zip.finish()?;
+
+    for stream in [false, true] {
+        let mut cmd = pulonia_in(&root);
+        cmd.args(["-b", "before.zip", "-a", "traversal.zip"]);
+        if stream {
+            cmd.arg("--stream");
+        }
+        cmd.assert().failure().stderr(predicate::str::contains(
+            "Entry has an absolute or parent-relative path: ../evil.txt",
+        ));
+    }
+    assert!(!root.parent().unwrap().join("evil.txt").exists());
+
+    pulonia_in(&root)
+        .args(["-b", "before.zip", "-a", "bomb.zip", "--max-ratio", "10"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "Entry zeros.bin exceeds the maximum compression ratio of 10",
+        ));
+
+    pulonia_in(&root)
+        .args([
+            "-b",
+            "before.zip",
+            "-a",
+            "bomb.zip",
+            "--max-entry-size",
+            "1M",
+        ])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "Entry zeros.bin is larger than 1048576 bytes",
+        ));
+
+    pulonia_in(&root)
+        .args(["-b", "before.zip", "-a", "before.zip", "--max-entries", "1"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Archive has more than 1 entries"));
+
+    #[cfg(unix)]
+    {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_size(0);
+        let mut builder = tar::Builder::new(File::create(root.join("escape.tar"))?);
+        builder.append_link(&mut header, "sub/link", "../../outside")?;
+        builder.finish()?;
+
+        pulonia_in(&root)
+            .args(["-b", "before.zip", "-a", "escape.tar"])
+            .assert()
+            .failure()
+            .stderr(predicate::str::contains(
+                "Link sub/link points outside the extraction root: ../../outside",
+            ));
+    }
+
+    fs::remove_dir_all(&root)?;
+    Ok(())
+}
+
+#[test]
+fn test_compression_level_and_method() -> Result<(), Box<dyn std::error::Error>> {
+    let root = create_workspace("method")?;
+
+    let before_dir = root.join("before");
+    fs::create_dir(&before_dir)?;
+    fs::write(before_dir.join("file1.txt"), "content A")?;
+
+    let after_dir = root.join("after");
+    fs::create_dir(&after_dir)?;
+    fs::write(after_dir.join("file1.txt"), "content B ".repeat(100))?;
+
+    for (method, expected) in [
+        ("stored", zip::CompressionMethod::Stored),
+        ("bzip2", zip::CompressionMethod::Bzip2),
+        ("zstd", zip::CompressionMethod::Zstd),
+    ] {
+        let output = format!("{}.zip", method);
+        pulonia_in(&root)
+            .args(["-b", "before", "-a", "after", "-o", &output])
+            .args(["--method", method])
+            .assert()
+            .success();
+        let mut archive = zip::ZipArchive::new(File::open(root.join(&output))?)?;
+        assert_eq!(archive.by_name("file1.txt")?.compression(), expected);
+    }
+
+    for (output, level) in [("best.tar.xz", "9"), ("fast.tar.gz", "1"), ("best.7z", "9")] {
+        pulonia_in(&root)
+            .args([
+                "-b", "before", "-a", "after", "-o", output, "--level", level,
+            ])
+            .assert()
+            .success();
+        assert!(root.join(output).exists());
+    }
+
+    // Rejected options are reported before the inputs are compared, so no migration
+    // record is left behind
+    for entry in fs::read_dir(&root)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "json") {
+            fs::remove_file(path)?;
+        }
+    }
+    pulonia_in(&root)
+        .args(["-b", "before", "-a", "after", "-f", "tar", "--level", "3"])
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains(
+            "tar patches are not compressed and take no level",
+        ))
+        .stdout(predicate::str::contains("after path").not());
+
+    pulonia_in(&root)
+        .args(["-b", "before", "-a", "after", "-o", "bad.zip"])
+        .args(["--method", "stored", "--level", "5"])
+        .assert()
+        .stderr(predicate::str::contains(
+            "stored zip patches are not compressed and take no level",
+        ));
+    assert!(!root.join("bad.zip").exists());
+
+    pulonia_in(&root)
+        .args(["-b", "before", "-a", "after", "-o", "bad.tar.gz"])
+        .args(["--method", "bzip2"])
+        .assert()
+        .stderr(predicate::str::contains(
+            "compression method bzip2 only applies to zip patches",
+        ));
+
+    pulonia_in(&root)
+        .args([
+            "-b", "before", "-a", "after", "-o", "bad.zip", "--level", "12",
+        ])
+        .assert()
+        .stderr(predicate::str::contains(
+            "deflate level must be within 0..=9, got 12",
+        ));
+
+    // Long-distance matching only applies to tar.zst patches
+    for (output, extra) in [
+        ("long.tar.gz", None),
+        ("long.tar.xz", None),
+        ("long.zip", Some(["--method", "zstd"])),
+        ("long.7z", None),
+    ] {
+        let mut cmd = pulonia_in(&root);
+        cmd.args(["-b", "before", "-a", "after", "-o", output, "--long"]);
+        if let Some(extra) = extra {
+            cmd.args(extra);
+        }
+        cmd.assert().code(2).stderr(predicate::str::contains(
+            "long-distance matching only applies to zstd-compressed tar patches",
+        ));
+        assert!(!root.join(output).exists());
+    }
+    assert!(read_migration(&root).is_err());
+
+    fs::remove_dir_all(&root)?;
+    Ok(())
+}
+
+#[test]
+fn test_compressed_payloads_are_stored_in_zip() -> Result<(), Box<dyn std::error::Error>> {
+    let root = create_workspace("store")?;
+
+    let before_dir = root.join("before");
+    fs::create_dir(&before_dir)?;
+    fs::write(before_dir.join("keep.txt"), "unchanged")?;
+
+    // Pseudo-random bytes that deflate cannot shrink.
+    let mut seed: u32 = 12345;
+    let noise: Vec<u8> = (0..64 * 1024)
+        .map(|_| {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (seed >> 16) as u8
+        })
+        .collect();
+
+    let after_dir = root.join("after");
+    fs::create_dir(&after_dir)?;
+    fs::write(after_dir.join("keep.txt"), "unchanged")?;
+    fs::write(after_dir.join("text.txt"), "compressible ".repeat(1000))?;
+    fs::write(after_dir.join("image.PNG"), "compressible ".repeat(1000))?;
+    fs::write(after_dir.join("noise.bin"), &noise)?;
+
+    let methods = |zip_file: &Path| -> Result<Vec<(String, zip::CompressionMethod)>, Box<dyn std::error::Error>> {
+        let mut archive = zip::ZipArchive::new(File::open(zip_file)?)?;
+        let mut methods = Vec::new();
+        for i in 0..archive.len() {
+            let entry = archive.by_index(i)?;
+            methods.push((entry.name().to_string(), entry.compression()));
+        }
+        methods.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(methods)
+    };
+
+    pulonia_in(&root)
+        .args(["-b", "before", "-a", "after", "-o", "default.zip"])
+        .assert()
+        .success();
+    assert_eq!(
+        methods(&root.join("default.zip"))?,
+        vec![
+            ("image.PNG".to_string(), zip::CompressionMethod::Stored),
+            ("noise.bin".to_string(), zip::CompressionMethod::Deflated),
+            ("text.txt".to_string(), zip::CompressionMethod::Deflated),
+        ]
+    );
+
+    pulonia_in(&root)
+        .args(["-b", "before", "-a", "after", "-o", "trial.zip"])
+        .args(["--min-saving", "10", "--store-extensions"])
+        .assert()
+        .success();
+    assert_eq!(
+        methods(&root.join("trial.zip"))?,
+        vec![
+            ("image.PNG".to_string(), zip::CompressionMethod::Deflated),
+            ("noise.bin".to_string(), zip::CompressionMethod::Stored),
+            ("text.txt".to_string(), zip::CompressionMethod::Deflated),
+        ]
+    );
+
+    fs::remove_dir_all(&root)?;
+    Ok(())
+}
+
+#[test]
+fn test_versioned_top_level_folders_are_aligned() -> Result<(), Box<dyn std::error::Error>> {
+    let root = create_workspace("strip")?;
+
+    let before_dir = root.join("before");
+    fs::create_dir_all(before_dir.join("app-1.0/lib"))?;
+    fs::write(before_dir.join("app-1.0/file1.txt"), "content A")?;
+    fs::write(before_dir.join("app-1.0/lib/file2.txt"), "content B")?;
+
+    let after_dir = root.join("after");
+    fs::create_dir_all(after_dir.join("app-1.1/lib"))?;
+    fs::write(after_dir.join("app-1.1/file1.txt"), "content A")?;
+    fs::write(after_dir.join("app-1.1/lib/file2.txt"), "content C")?;
+
+    create_zip(&before_dir, &root.join("before.zip"))?;
+    create_zip(&after_dir, &root.join("after.zip"))?;
+
+    for (stream, output) in [(false, "patch.zip"), (true, "stream.zip")] {
+        let mut cmd = pulonia_in(&root);
+        cmd.args(["-b", "before.zip", "-a", "after.zip", "-o", output]);
+        if stream {
+            cmd.arg("--stream");
+        }
+        cmd.arg("-v");
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains(
+                "Aligned top-level folders: app-1.0 -> app-1.1",
+            ))
+            .stderr(predicate::str::contains(
+                "aligned app-1.1: compared with previous top-level folder app-1.0",
+            ));
+
+        let json = read_migration(&root)?;
+        assert_eq!(json["strip"]["before"], "app-1.0");
+        assert_eq!(json["strip"]["after"], "app-1.1");
+        assert!(json["update"]["lib"].get("file2.txt").is_some());
+        assert!(json["deleted"].as_array().unwrap().is_empty());
+        assert_eq!(zip_entry_names(&root.join(output))?, vec!["lib/file2.txt"]);
+    }
+
+    pulonia_in(&root)
+        .args([
+            "-b",
+            "before.zip",
+            "-a",
+            "after.zip",
+            "--strip-components",
+            "2",
+        ])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "Cannot strip 2 components: app-1.0 does not contain exactly one directory",
+        ));
+    fs::remove_dir_all(&root)?;
+
+    // Unrelated folder names are not aligned, only logged with a warning
+    let root = create_workspace("unaligned")?;
+    fs::create_dir_all(root.join("before/server"))?;
+    fs::write(root.join("before/server/file1.txt"), "content A")?;
+    fs::create_dir_all(root.join("after/client"))?;
+    fs::write(root.join("after/client/file1.txt"), "content A")?;
+    pulonia_in(&root)
+        .args(["-b", "before", "-a", "after", "-o", "patch.zip"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Aligned top-level folders").not())
+        .stderr(predicate::str::contains(
+            "WARN  unaligned client: not compared with previous top-level folder server",
+        ));
+    let json = read_migration(&root)?;
+    assert!(json.get("strip").is_none());
+    assert_eq!(json["deleted"], serde_json::json!(["server/file1.txt"]));
+    assert!(json["update"]["client"].get("file1.txt").is_some());
+
+    fs::remove_dir_all(&root)?;
+    Ok(())
+}
+
+#[test]
+fn test_sub_tree_roots() -> Result<(), Box<dyn std::error::Error>> {
+    let root = create_workspace("subtree")?;
+
+    let before_dir = root.join("before");
+    fs::create_dir_all(before_dir.join("plugins/extra"))?;
+    fs::write(before_dir.join("core.txt"), "core A")?;
+    fs::write(before_dir.join("plugins/a.txt"), "plugin A")?;
+    fs::write(before_dir.join("plugins/extra/b.txt"), "plugin B")?;
+
+    let after_dir = root.join("after");
+    fs::create_dir_all(after_dir.join("plugins/extra"))?;
+    fs::write(after_dir.join("core.txt"), "core B")?;
+    fs::write(after_dir.join("plugins/a.txt"), "plugin A")?;
+    fs::write(after_dir.join("plugins/extra/b.txt"), "plugin C")?;
+
+    create_zip(&before_dir, &root.join("before.zip"))?;
+    create_tar(&after_dir, &root.join("after.tar"))?;
+
+    for (inputs, extra, output) in [
+        (["before.zip", "after.tar"], None, "patch.zip"),
+        (["before.zip", "after.tar"], Some("--stream"), "stream.zip"),
+        (["before", "after"], None, "dir.zip"),
+    ] {
+        let mut cmd = pulonia_in(&root);
+        cmd.args(["-b", inputs[0], "-a", inputs[1], "-o", output])
+            .args(["--before-root", "plugins/", "--after-root", "./plugins"]);
+        if let Some(extra) = extra {
+            cmd.arg(extra);
+        }
+        cmd.assert().success();
+
+        let json = read_migration(&root)?;
+        assert_eq!(json["root"]["before"], "plugins");
+        assert!(json["update"]["extra"].get("b.txt").is_some());
+        assert!(json["update"].get("core.txt").is_none());
+        assert_eq!(zip_entry_names(&root.join(output))?, vec!["extra/b.txt"]);
+    }
+
+    pulonia_in(&root)
+        .args([
+            "-b",
+            "before.zip",
+            "-a",
+            "after.tar",
+            "--after-root",
+            "missing",
+        ])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "Directory missing not found in archive",
+        ));
+
+    fs::remove_dir_all(&root)?;
+    Ok(())
+}
+
+#[test]
+fn test_nested_archives() -> Result<(), Box<dyn std::error::Error>> {
+    let root = create_workspace("nested")?;
+
+    for (side, class) in [("before", "class A"), ("after", "class B")] {
+        let jar_dir = root.join(format!("{}_jar", side));
+        fs::create_dir_all(jar_dir.join("META-INF"))?;
+        fs::create_dir_all(jar_dir.join("com"))?;
+        fs::write(
+            jar_dir.join("META-INF/MANIFEST.MF"),
+            "Manifest-Version: 1.0",
+        )?;
+        fs::write(jar_dir.join("com/A.class"), class)?;
+
+        let side_dir = root.join(side);
+        fs::create_dir_all(side_dir.join("lib"))?;
+        fs::write(side_dir.join("readme.txt"), "readme")?;
+        create_zip(&jar_dir, &side_dir.join("lib/app.jar"))?;
+        create_zip(&side_dir, &root.join(format!("{}.zip", side)))?;
+    }
+
+    for (inputs, extra, output) in [
+        (["before.zip", "after.zip"], None, "patch.zip"),
+        (["before.zip", "after.zip"], Some("--stream"), "stream.zip"),
+        (["before", "after"], None, "dir.zip"),
+    ] {
+        let mut cmd = pulonia_in(&root);
+        cmd.args(["-b", inputs[0], "-a", inputs[1], "-o", output])
+            .args(["--nested", "*.jar"]);
+        if let Some(extra) = extra {
+            cmd.arg(extra);
+        }
+        cmd.assert().success();
+
+        let json = read_migration(&root)?;
+        assert!(
+            json["update"]["lib"]["app.jar"]["com"]
+                .get("A.class")
+                .is_some()
+        );
+        assert!(json["update"]["lib"]["app.jar"].get("META-INF").is_none());
+        assert_eq!(json["archives"]["lib/app.jar"], "zip");
+        assert_eq!(
+            zip_entry_names(&root.join(output))?,
+            vec!["lib/app.jar/com/A.class"]
+        );
+    }
+
+    // Without --nested the inner archive is replaced as a whole
+    pulonia_in(&root)
+        .args(["-b", "before.zip", "-a", "after.zip", "-o", "plain.zip"])
+        .assert()
+        .success();
+    let json = read_migration(&root)?;
+    assert!(json["update"]["lib"].get("app.jar").is_some());
+    assert!(json.get("archives").is_none());
+    assert_eq!(
+        zip_entry_names(&root.join("plain.zip"))?,
+        vec!["lib/app.jar"]
+    );
+
+    // Archives nested three deep: deep/level1.zip holds level2.zip, which holds level3.zip
+    let mut inner = root.join("level3");
+    fs::create_dir_all(&inner)?;
+    fs::write(inner.join("leaf.txt"), "leaf")?;
+    for level in [3, 2, 1] {
+        let wrapper = root.join(format!("wrap{}", level));
+        fs::create_dir_all(&wrapper)?;
+        create_zip(&inner, &wrapper.join(format!("level{}.zip", level)))?;
+        inner = wrapper;
+    }
+    fs::create_dir_all(root.join("deep"))?;
+    fs::rename(
+        inner.join("level1.zip"),
+        root.join("deep").join("level1.zip"),
+    )?;
+    create_zip(&root.join("deep"), &root.join("deep.zip"))?;
+
+    for (inputs, extra) in [
+        (["before.zip", "deep.zip"], Some("--stream")),
+        (["before", "deep"], None),
+    ] {
+        let mut cmd = pulonia_in(&root);
+        cmd.args(["-b", inputs[0], "-a", inputs[1], "-o", "deep_patch.zip"])
+            .args(["--nested", "*.zip"]);
+        if let Some(extra) = extra {
+            cmd.arg(extra);
+        }
+        cmd.args(["--max-nesting", "2"])
+            .assert()
+            .code(2)
+            .stderr(predicate::str::contains(
+                "Nested archive level3.zip is more than 2 archives deep",
+            ));
+    }
+    // The entry cap counts the entries of nested archives too: deep.zip has one entry
+    pulonia_in(&root)
+        .args(["-b", "before.zip", "-a", "deep.zip", "-o", "deep_patch.zip"])
+        .args(["--nested", "*.zip", "--stream", "--max-entries", "3"])
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains("Archive has more than 3 entries"));
+    pulonia_in(&root)
+        .args(["-b", "before.zip", "-a", "deep.zip", "-o", "deep_patch.zip"])
+        .args(["--nested", "*.zip", "--stream"])
+        .assert()
+        .success();
+    let json = read_migration(&root)?;
+    assert_eq!(json["archives"]["level1.zip/level2.zip/level3.zip"], "zip");
+
+    fs::remove_dir_all(&root)?;
+    Ok(())
+}
+
+/// A toy format storing one `name\tcontent` line per file.
+struct LinesFormat;
+
+impl pulonia::archive::ArchiveReader for LinesFormat {
+    fn read(
+        &self,
+        input_path: &str,
+        tracker: &mut pulonia::limits::LimitTracker,
+        visit: &mut pulonia::archive::EntryVisitor,
+    ) -> Result<(), pulonia::compress::DecompressError> {
+        let text = fs::read_to_string(input_path)?;
+        for line in text.lines().skip(1) {
+            let (name, content) = line.split_once('\t').unwrap();
+            let Some(name) = tracker.entry_name(Path::new(name))? else {
+                continue;
+            };
+            let entry = pulonia::archive::ArchiveEntry {
+                name,
+                kind: pulonia::archive::EntryKind::File,
+                mode: None,
+                zip_meta: None,
+            };
+            let mut data = content.as_bytes();
+            visit(&entry, &mut tracker.reader(&entry.name, &mut data))?;
+        }
+        Ok(())
+    }
+}
+
+impl pulonia::archive::ArchiveWriter for LinesFormat {
+    fn write(
+        &self,
+        input_path: &str,
+        output_path: &str,
+        _options: &pulonia::compress::CompressOptions,
+    ) -> Result<(), pulonia::compress::DecompressError> {
+        let mut text = String::from("LINES\n");
+        for entry in walkdir::WalkDir::new(input_path).sort_by_file_name() {
+            let entry = entry.map_err(std::io::Error::other)?;
+            if entry.file_type().is_file() {
+                let name = entry.path().strip_prefix(input_path).unwrap();
+                let content = fs::read_to_string(entry.path())?;
+                text += &format!("{}\t{}\n", name.to_string_lossy(), content);
+            }
+        }
+        fs::write(output_path, text)?;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_registered_format() -> Result<(), Box<dyn std::error::Error>> {
+    use pulonia::archive::{ArchiveFormat, register_format};
+    use pulonia::compress;
+
+    let root = create_workspace("registry")?;
+    register_format(
+        ArchiveFormat::new("lines")
+            .with_extensions(&["lines"])
+            .with_magic(0, b"LINES\n")
+            .with_reader(LinesFormat)
+            .with_writer(LinesFormat),
+    );
+
+    let src_dir = root.join("src");
+    fs::create_dir_all(src_dir.join("sub"))?;
+    fs::write(src_dir.join("a.txt"), "alpha")?;
+    fs::write(src_dir.join("sub/b.txt"), "beta")?;
+
+    let output = root.join("out.lines");
+    compress::compress(
+        src_dir.to_str().unwrap(),
+        output.to_str().unwrap(),
+        "lines",
+        &compress::CompressOptions::default(),
+    )?;
+    // Detected from the signature even under another name.
+    let renamed = root.join("out.bin");
+    fs::rename(&output, &renamed)?;
+    assert_eq!(
+        compress::detect_format(renamed.to_str().unwrap(), None)?,
+        "lines"
+    );
+
+    let extracted = root.join("extracted");
+    compress::decompress(
+        renamed.to_str().unwrap(),
+        extracted.to_str().unwrap(),
+        None,
+        &pulonia::limits::ExtractLimits::default(),
+        None,
+    )?;
+    assert_eq!(fs::read_to_string(extracted.join("sub/b.txt"))?, "beta");
+
+    let with_method = compress::CompressOptions {
+        level: Some(3),
+        method: Some("zstd".to_string()),
+        ..Default::default()
+    };
+    let err = compress::compress(
+        src_dir.to_str().unwrap(),
+        root.join("bad.lines").to_str().unwrap(),
+        "lines",
+        &with_method,
+    )
+    .unwrap_err();
+    assert!(err.to_string().contains("only applies to zip patches"));
+
+    fs::remove_dir_all(&root)?;
+    Ok(())
+}
+
+#[test]
+fn test_config_ignore_and_include() -> Result<(), Box<dyn std::error::Error>> {
+    let root = create_workspace("config")?;
+
+    let before_dir = root.join("before");
+    fs::create_dir_all(before_dir.join("cache"))?;
+    fs::create_dir_all(before_dir.join("docs"))?;
+    fs::write(before_dir.join("app.txt"), "app A")?;
+    fs::write(before_dir.join("run.log"), "log A")?;
+    fs::write(before_dir.join("old.bak"), "backup")?;
+    fs::write(before_dir.join("cache/data"), "cache A")?;
+    fs::write(before_dir.join("docs/guide.md"), "guide A")?;
+
+    let after_dir = root.join("after");
+    fs::create_dir_all(after_dir.join("cache"))?;
+    fs::create_dir_all(after_dir.join("docs"))?;
+    fs::write(after_dir.join("app.txt"), "app B")?;
+    fs::write(after_dir.join("run.log"), "log B")?;
+    fs::write(after_dir.join("cache/data"), "cache B")?;
+    fs::write(after_dir.join("docs/guide.md"), "guide B")?;
+
+    create_zip(&before_dir, &root.join("before.zip"))?;
+    create_zip(&after_dir, &root.join("after.zip"))?;
+
+    fs::write(
+        root.join("filters.json"),
+        r#"{ "ignore": ["cache", "\\.log$", "old.bak"] }"#,
+    )?;
+    for (extra, output) in [
+        (None, "patch.zip"),
+        (Some("--stream"), "stream.zip"),
+        (Some("--zip-prefilter"), "prefilter.zip"),
+    ] {
+        let mut cmd = pulonia_in(&root);
+        cmd.args(["-b", "before.zip", "-a", "after.zip", "-o", output])
+            .args(["--config", "filters.json"]);
+        if let Some(extra) = extra {
+            cmd.arg(extra);
+        }
+        cmd.assert().success();
+
+        let json = read_migration(&root)?;
+        assert!(json["update"].get("app.txt").is_some());
+        assert!(json["update"].get("run.log").is_none());
+        assert!(json["update"].get("cache").is_none());
+        assert!(json["deleted"].as_array().unwrap().is_empty());
+        assert_eq!(
+            zip_entry_names(&root.join(output))?,
+            vec!["app.txt", "docs/guide.md"]
+        );
+    }
+
+    // pulonia.json in the working directory is picked up without --config
+    fs::write(root.join("pulonia.json"), r#"{ "include": ["docs"] }"#)?;
+    pulonia_in(&root)
+        .args(["-b", "before", "-a", "after", "-o", "auto.zip"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Config: pulonia.json"));
+    let json = read_migration(&root)?;
+    assert!(json["update"]["docs"].get("guide.md").is_some());
+    assert!(json["update"].get("app.txt").is_none());
+    assert_eq!(
+        zip_entry_names(&root.join("auto.zip"))?,
+        vec!["docs/guide.md"]
+    );
+
+    fs::write(root.join("pulonia.json"), r#"{ "ignore": ["[unclosed"] }"#)?;
+    pulonia_in(&root)
+        .args(["-b", "before", "-a", "after"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Invalid regex '[unclosed'"));
+
+    fs::remove_dir_all(&root)?;
+    Ok(())
+}
+
+#[test]
+fn test_puloniaignore() -> Result<(), Box<dyn std::error::Error>> {
+    let root = create_workspace("puloniaignore")?;
+
+    for (side, content) in [("before", "A"), ("after", "B")] {
+        let dir = root.join(side);
+        for file in [
+            "app.txt",
+            "build/app.o",
+            "a.log",
+            "keep.log",
+            "root.txt",
+            "sub/root.txt",
+            "docs/x/y/draft.md",
+            "extra/data",
+        ] {
+            let path = dir.join(file);
+            fs::create_dir_all(path.parent().unwrap())?;
+            fs::write(path, content)?;
+        }
+    }
+    fs::write(root.join("after/.puloniaignore"), "extra/\n")?;
+    create_zip(&root.join("before"), &root.join("before.zip"))?;
+    create_zip(&root.join("after"), &root.join("after.zip"))?;
+
+    fs::write(
+        root.join(".puloniaignore"),
+        "# build output\nbuild/\n*.log\n!keep.log\n/root.txt\ndocs/**/draft.md\n",
+    )?;
+    for extra in [None, Some("--stream")] {
+        let mut cmd = pulonia_in(&root);
+        cmd.args(["-b", "before.zip", "-a", "after.zip", "--archive-ignore"]);
+        if let Some(extra) = extra {
+            cmd.arg(extra);
+        }
+        cmd.assert().success().stdout(predicate::str::contains(
+            "Ignore rules: after.zip:.puloniaignore",
+        ));
+
+        let json = read_migration(&root)?;
+        let update = json["update"].as_object().unwrap();
+        let mut names: Vec<&str> = update.keys().map(|k| k.as_str()).collect();
+        names.sort();
+        assert_eq!(names, vec![".puloniaignore", "app.txt", "keep.log", "sub"]);
+        assert!(json["update"]["sub"].get("root.txt").is_some());
+    }
+
+    pulonia_in(&root)
+        .args([
+            "check-ignore",
+            "build/app.o",
+            "keep.log",
+            "docs/a/b/draft.md",
+        ])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "ignored: build/app.o (.puloniaignore:2: build/)",
+        ))
+        .stdout(predicate::str::contains(
+            "not ignored: keep.log (.puloniaignore:4: !keep.log)",
+        ))
+        .stdout(predicate::str::contains(
+            "ignored: docs/a/b/draft.md (.puloniaignore:6: docs/**/draft.md)",
+        ));
+    pulonia_in(&root)
+        .args(["check-ignore", "sub/root.txt"])
+        .assert()
+        .code(1)
+        .stdout(predicate::str::contains("not ignored: sub/root.txt"));
+
+    fs::remove_dir_all(&root)?;
+    Ok(())
+}
+
+#[test]
+fn test_preserved_paths() -> Result<(), Box<dyn std::error::Error>> {
+    let root = create_workspace("preserve")?;
+
+    let before_dir = root.join("before");
+    fs::create_dir_all(before_dir.join("config"))?;
+    fs::create_dir_all(before_dir.join("saves"))?;
+    fs::write(before_dir.join("app.txt"), "app A")?;
+    fs::write(before_dir.join("config/app.ini"), "defaults A")?;
+    fs::write(before_dir.join("saves/slot1"), "save")?;
+    fs::write(before_dir.join("user.local.json"), "{}")?;
+    fs::write(before_dir.join("old.txt"), "old")?;
+
+    let after_dir = root.join("after");
+    fs::create_dir_all(after_dir.join("config"))?;
+    fs::write(after_dir.join("app.txt"), "app B")?;
+    fs::write(after_dir.join("config/app.ini"), "defaults B")?;
+    fs::write(after_dir.join("config/new.ini"), "new")?;
+
+    fs::write(
+        root.join("pulonia.json"),
+        r#"{ "preserve": ["config/", "saves/", "*.local.json"] }"#,
+    )?;
+    pulonia_in(&root)
+        .args(["-b", "before", "-a", "after", "-o", "patch.zip"])
+        .assert()
+        .success();
+
+    let json = read_migration(&root)?;
+    assert_eq!(json["deleted"], serde_json::json!(["old.txt"]));
+    assert_eq!(json["update"]["config"]["app.ini"]["preserve"], true);
+    assert_eq!(json["update"]["config"]["new.ini"]["preserve"], true);
+    assert!(json["update"]["app.txt"].get("preserve").is_none());
+    assert_eq!(
+        zip_entry_names(&root.join("patch.zip"))?,
+        vec!["app.txt", "config/app.ini", "config/new.ini"]
+    );
+
+    fs::remove_dir_all(&root)?;
+    Ok(())
+}
+
+#[test]
+fn test_project_config_profiles_and_env() -> Result<(), Box<dyn std::error::Error>> {
+    let root = create_workspace("project_config")?;
+
+    let before_dir = root.join("before");
+    fs::create_dir_all(&before_dir)?;
+    fs::write(before_dir.join("app.txt"), "app A")?;
+    fs::write(before_dir.join("run.log"), "log A")?;
+
+    let after_dir = root.join("after");
+    fs::create_dir_all(&after_dir)?;
+    fs::write(after_dir.join("app.txt"), "app B")?;
+    fs::write(after_dir.join("run.log"), "log B")?;
+
+    fs::write(
+        root.join("pulonia.toml"),
+        r#"
+before = "before"
+after = "after"
+output = "patch.zip"
+ignore = ["run.log"]
+
+[profile.nightly]
+output = "nightly.zip"
+level = 1
+"#,
+    )?;
+
+    // Every option comes from the config file
+    pulonia_in(&root)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Config: pulonia.toml"));
+    assert_eq!(zip_entry_names(&root.join("patch.zip"))?, vec!["app.txt"]);
+
+    // Profile over file, environment over profile, command line over environment
+    pulonia_in(&root)
+        .args(["--profile", "nightly"])
+        .assert()
+        .success();
+    assert!(root.join("nightly.zip").exists());
+    pulonia_in(&root)
+        .args(["--profile", "nightly"])
+        .env("PULONIA_OUTPUT", "env.zip")
+        .assert()
+        .success();
+    assert!(root.join("env.zip").exists());
+    pulonia_in(&root)
+        .args(["-o", "cli.zip"])
+        .env("PULONIA_PROFILE", "nightly")
+        .env("PULONIA_OUTPUT", "env.zip")
+        .assert()
+        .success();
+    assert!(root.join("cli.zip").exists());
+
+    pulonia_in(&root)
+        .args(["--profile", "nightly", "config", "show"])
+        .env("PULONIA_LEVEL", "3")
+        .env("PULONIA_STREAM", "true")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "before = \"before\"  # pulonia.toml",
+        ))
+        .stdout(predicate::str::contains(
+            "output = \"nightly.zip\"  # profile 'nightly' in pulonia.toml",
+        ))
+        .stdout(predicate::str::contains("level = 3  # PULONIA_LEVEL"))
+        .stdout(predicate::str::contains("stream = true  # PULONIA_STREAM"))
+        .stdout(predicate::str::contains("ignore = [\"run.log\"]"));
+
+    // Values are validated like command-line arguments
+    pulonia_in(&root)
+        .env("PULONIA_MIN_SAVING", "150")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("--min-saving"))
+        .stderr(predicate::str::contains("(PULONIA_MIN_SAVING)"));
+    // ...but only when diffing, and unrelated PULONIA_* variables are skipped
+    pulonia_in(&root)
+        .args(["check-ignore", "run.log"])
+        .env("PULONIA_MIN_SAVING", "150")
+        .assert()
+        .code(0);
+    pulonia_in(&root)
+        .args(["-o", "unrelated.zip"])
+        .env("PULONIA_UNRELATED_SETTING", "x")
+        .assert()
+        .success()
+        .stderr(predicate::str::contains(
+            "Ignoring environment variable PULONIA_UNRELATED_SETTING: not a pulonia option",
+        ));
+    // Logging options are settings too
+    pulonia_in(&root)
+        .args(["-o", "logged.zip"])
+        .env("PULONIA_QUIET", "true")
+        .env("PULONIA_VERBOSE", "1")
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("INFO  modified app.txt"));
+    pulonia_in(&root)
+        .args(["config", "show"])
+        .env("PULONIA_VERBOSE", "2")
+        .env("PULONIA_LOG_FORMAT", "json")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("verbose = 2  # PULONIA_VERBOSE"))
+        .stdout(predicate::str::contains(
+            "log-format = \"json\"  # PULONIA_LOG_FORMAT",
+        ));
+    pulonia_in(&root)
+        .args(["--profile", "release"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("profile 'release' is not defined"));
+
+    fs::remove_file(root.join("pulonia.toml"))?;
+    fs::write(
+        root.join("pulonia.yaml"),
+        "before: before\nafter: after\noutput: yaml.zip\ncolour: true\n",
+    )?;
+    pulonia_in(&root)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "unknown option 'colour' (pulonia.yaml)",
+        ));
+    fs::write(
+        root.join("pulonia.yaml"),
+        "before: before\nafter: after\noutput: yaml.zip\nignore:\n- run.log\n",
+    )?;
+    pulonia_in(&root).assert().success();
+    assert_eq!(zip_entry_names(&root.join("yaml.zip"))?, vec!["app.txt"]);
+
+    // Syntax errors report their position
+    fs::remove_file(root.join("pulonia.yaml"))?;
+    fs::write(
+        root.join("pulonia.toml"),
+        "before = \"before\"\nafter = after\n",
+    )?;
+    pulonia_in(&root)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("pulonia.toml"))
+        .stderr(predicate::str::contains("line 2"));
+
+    fs::remove_dir_all(&root)?;
+    Ok(())
+}
+
+#[test]
+fn test_hooks_are_packaged_and_listed() -> Result<(), Box<dyn std::error::Error>> {
+    let root = create_workspace("hooks")?;
+
+    let before_dir = root.join("before");
+    fs::create_dir_all(&before_dir)?;
+    fs::write(before_dir.join("app.txt"), "app A")?;
+    let after_dir = root.join("after");
+    fs::create_dir_all(&after_dir)?;
+    fs::write(after_dir.join("app.txt"), "app B")?;
+
+    fs::create_dir_all(root.join("scripts"))?;
+    fs::write(root.join("scripts/migrate.sh"), "#!/bin/sh\necho migrate\n")?;
+    fs::write(
+        root.join("pulonia.toml"),
+        r#"
+[hooks]
+pre_apply = ["systemctl stop app"]
+post_apply = [
+    { script = "scripts/migrate.sh", timeout = 600 },
+    { command = "systemctl start app" },
+]
+"#,
+    )?;
+
+    pulonia_in(&root)
+        .args(["-b", "before", "-a", "after", "-o", "patch.zip"])
+        .assert()
+        .success();
+
+    let json = read_migration(&root)?;
+    let hooks = json["hooks"].as_array().unwrap();
+    assert_eq!(hooks.len(), 3);
+    assert_eq!(hooks[0]["phase"], "pre_apply");
+    assert_eq!(hooks[0]["command"], "systemctl stop app");
+    assert_eq!(hooks[0]["timeout"], 300);
+    assert_eq!(hooks[1]["phase"], "post_apply");
+    assert_eq!(hooks[1]["script"], ".pulonia/hooks/post_apply/1_migrate.sh");
+    assert_eq!(hooks[1]["timeout"], 600);
+    assert_eq!(hooks[1]["hash"].as_str().unwrap().len(), 64);
+    assert_eq!(hooks[2]["command"], "systemctl start app");
+    assert_eq!(
+        zip_entry_names(&root.join("patch.zip"))?,
+        vec![".pulonia/hooks/post_apply/1_migrate.sh", "app.txt"]
+    );
+
+    fs::write(
+        root.join("pulonia.toml"),
+        "[hooks]\npre_install = [\"true\"]\n",
+    )?;
+    pulonia_in(&root)
+        .args(["-b", "before", "-a", "after"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("unknown hook phase 'pre_install'"));
+
+    fs::write(
+        root.join("pulonia.toml"),
+        "[hooks]\npost_apply = [{ script = \"scripts/missing.sh\" }]\n",
+    )?;
+    pulonia_in(&root)
+        .args(["-b", "before", "-a", "after"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("hook script 'scripts/missing.sh'"));
+
+    fs::remove_dir_all(&root)?;
+    Ok(())
+}
+
+#[test]
+fn test_diff_subcommand() -> Result<(), Box<dyn std::error::Error>> {
+    let root = create_workspace("diff_subcommand")?;
+
+    let before_dir = root.join("before");
+    fs::create_dir_all(&before_dir)?;
+    fs::write(before_dir.join("app.txt"), "app A")?;
+    let after_dir = root.join("after");
+    fs::create_dir_all(&after_dir)?;
+    fs::write(after_dir.join("app.txt"), "app B")?;
+    fs::write(after_dir.join("new.txt"), "new")?;
+
+    pulonia_in(&root)
+        .args(["diff", "-b", "before", "-a", "after", "-o", "sub.zip"])
+        .assert()
+        .success();
+    // Without a subcommand the same flags still run `diff`
+    pulonia_in(&root)
+        .args(["-b", "before", "-a", "after", "-o", "legacy.zip"])
+        .assert()
+        .success();
+    // Options before and after `diff` are merged
+    pulonia_in(&root)
+        .args(["-b", "before", "-o", "ignored.zip", "diff", "-a", "after"])
+        .args(["-o", "mixed.zip"])
+        .assert()
+        .success();
+
+    for patch in ["sub.zip", "legacy.zip", "mixed.zip"] {
+        assert_eq!(
+            zip_entry_names(&root.join(patch))?,
+            vec!["app.txt", "new.txt"]
+        );
+    }
+    assert!(!root.join("ignored.zip").exists());
+
+    fs::remove_dir_all(&root)?;
+    Ok(())
+}
+
+#[test]
+fn test_inspect_patch() -> Result<(), Box<dyn std::error::Error>> {
+    let root = create_workspace("inspect")?;
+
+    let before_dir = root.join("before");
+    fs::create_dir_all(&before_dir)?;
+    fs::write(before_dir.join("app.txt"), "app A")?;
+    fs::write(before_dir.join("old.txt"), "old")?;
+    let after_dir = root.join("after");
+    fs::create_dir_all(after_dir.join("lib"))?;
+    fs::write(after_dir.join("app.txt"), "app B")?;
+    fs::write(after_dir.join("lib/new.txt"), "new file")?;
+
+    pulonia_in(&root)
+        .args(["diff", "-b", "before", "-a", "after", "-o", "patch.zip"])
+        .assert()
+        .success();
+    let migration_name = fs::read_dir(&root)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .find(|name| name.starts_with("migration_"))
+        .unwrap();
+
+    pulonia_in(&root)
+        .args(["inspect", "patch.zip", "--migration", &migration_name])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Entries: 2 files, 13 bytes"))
+        .stdout(predicate::str::contains("lib/new.txt"))
+        .stdout(predicate::str::contains("Updated: 2 (0 preserved)"))
+        .stdout(predicate::str::contains("Deleted: 1"))
+        .stdout(predicate::str::contains(
+            "The patch matches the migration record.",
+        ));
+
+    // Without a migration record only the payload is listed
+    pulonia_in(&root)
+        .args(["inspect", "patch.zip"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("app.txt"));
+
+    // A patch that drifted from its migration record
+    let tampered = root.join("tampered");
+    fs::create_dir_all(&tampered)?;
+    fs::write(tampered.join("app.txt"), "app C")?;
+    fs::write(tampered.join("extra.txt"), "extra")?;
+    create_zip(&tampered, &root.join("tampered.zip"))?;
+    pulonia_in(&root)
+        .args(["inspect", "tampered.zip", "--migration", &migration_name])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("hash mismatch: app.txt"))
+        .stderr(predicate::str::contains(
+            "payload file has no migration entry: extra.txt",
+        ))
+        .stderr(predicate::str::contains(
+            "migration entry has no payload file: lib/new.txt",
+        ))
+        .stderr(predicate::str::contains("Found 3 inconsistencies."));
+
+    fs::remove_dir_all(&root)?;
+    Ok(())
+}
+
+#[test]
+fn test_exit_codes() -> Result<(), Box<dyn std::error::Error>> {
+    let root = create_workspace("exit_codes")?;
+
+    let before_dir = root.join("before");
+    fs::create_dir_all(&before_dir)?;
+    fs::write(before_dir.join("app.txt"), "app A")?;
+    let after_dir = root.join("after");
+    fs::create_dir_all(&after_dir)?;
+    fs::write(after_dir.join("app.txt"), "app B")?;
+
+    // Patch created
+    pulonia_in(&root)
+        .args(["-b", "before", "-a", "after", "-o", "update.zip"])
+        .assert()
+        .code(0);
+    // Identical inputs write nothing
+    pulonia_in(&root)
+        .args(["-b", "before", "-a", "before", "-o", "same.zip"])
+        .assert()
+        .code(1)
+        .stdout(predicate::str::contains("The two files are identical."));
+    assert!(!root.join("same.zip").exists());
+
+    // Input errors
+    pulonia_in(&root)
+        .args(["-b", "missing", "-a", "after"])
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains("Invalid previous version path"));
+    pulonia_in(&root)
+        .args(["-b", "before"])
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains(
+            "Both current and previous version paths must be provided",
+        ));
+    pulonia_in(&root)
+        .args(["-b", "before", "-a", "after", "--nested", "[bad"])
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains("Invalid nested archive glob"));
+
+    // I/O failure: the patch path is taken by a directory
+    fs::create_dir_all(root.join("blocked.zip"))?;
+    pulonia_in(&root)
+        .args(["-b", "before", "-a", "after", "-o", "blocked.zip"])
+        .assert()
+        .code(3)
+        .stderr(predicate::str::contains("Failed to create patch file"));
+
+    fs::remove_dir_all(&root)?;
+    Ok(())
+}
+
+#[test]
+fn test_quiet_and_verbose_resolution() -> Result<(), Box<dyn std::error::Error>> {
+    let root = create_workspace("progress")?;
+    fs::write(root.join("pulonia.toml"), "quiet = true\nverbose = 1\n")?;
+
+    // The file, then the environment, then the command line decide
+    pulonia_in(&root)
+        .args(["config", "show"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("quiet = true  # pulonia.toml"))
+        .stdout(predicate::str::contains("verbose = 1  # pulonia.toml"));
+    pulonia_in(&root)
+        .args(["config", "show"])
+        .env("PULONIA_QUIET", "false")
+        .env("PULONIA_VERBOSE", "2")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("quiet = false  # PULONIA_QUIET"))
+        .stdout(predicate::str::contains("verbose = 2  # PULONIA_VERBOSE"));
+    pulonia_in(&root)
+        .args(["-q", "-vvv", "config", "show"])
+        .env("PULONIA_QUIET", "false")
+        .env("PULONIA_VERBOSE", "2")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("quiet = true  # command line"))
+        .stdout(predicate::str::contains("verbose = 3  # command line"));
+
+    // Invalid values are rejected by every command, since logging starts first
+    pulonia_in(&root)
+        .args(["check-ignore", "x"])
+        .env("PULONIA_QUIET", "maybe")
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains("(PULONIA_QUIET)"));
+
+    fs::remove_dir_all(&root)?;
+    Ok(())
+}
+
+#[test]
+fn test_decision_log() -> Result<(), Box<dyn std::error::Error>> {
+    let root = create_workspace("decision_log")?;
+
+    let before_dir = root.join("before");
+    fs::create_dir_all(before_dir.join("logs"))?;
+    fs::write(before_dir.join("same.txt"), "same")?;
+    fs::write(before_dir.join("app.txt"), "app A")?;
+    fs::write(before_dir.join("old.txt"), "old")?;
+    fs::write(before_dir.join("logs/run.log"), "log A")?;
+    let after_dir = root.join("after");
+    fs::create_dir_all(after_dir.join("logs"))?;
+    fs::write(after_dir.join("same.txt"), "same")?;
+    fs::write(after_dir.join("app.txt"), "app B")?;
+    fs::write(after_dir.join("new.txt"), "new")?;
+    fs::write(after_dir.join("logs/run.log"), "log B")?;
+    fs::write(root.join(".puloniaignore"), "logs/\n")?;
+
+    // Default: warnings only
+    pulonia_in(&root)
+        .args(["-b", "before", "-a", "after", "-o", "quiet.zip"])
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("added new.txt").not());
+
+    // -v logs the files that go into the migration record
+    pulonia_in(&root)
+        .args(["-v", "-b", "before", "-a", "after", "-o", "info.zip"])
+        .assert()
+        .success()
+        .stderr(predicate::str::contains(
+            "INFO  added new.txt: not in the previous version",
+        ))
+        .stderr(predicate::str::contains(
+            "INFO  deleted old.txt: not in the current version",
+        ))
+        .stderr(predicate::str::contains("modified app.txt: hash "))
+        .stderr(predicate::str::contains("unchanged same.txt").not());
+
+    // -vv adds unchanged and ignored files; JSON lines go to the log file
+    pulonia_in(&root)
+        .args(["-vv", "--log-file", "audit.jsonl", "--log-format", "json"])
+        .args(["-b", "before", "-a", "after", "-o", "debug.zip"])
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("added new.txt").not());
+    let log = fs::read_to_string(root.join("audit.jsonl"))?;
+
+    // Like the patch, the log file must stay inside the working directory
+    pulonia_in(&root)
+        .args(["-v", "--log-file", "../outside.log"])
+        .args(["-b", "before", "-a", "after", "-o", "escape.zip"])
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains(
+            "Unsafe log file path: ../outside.log",
+        ));
+    assert!(!root.join("../outside.log").exists());
+    let records: Vec<serde_json::Value> = log
+        .lines()
+        .map(serde_json::from_str)
+        .collect::<Result<_, _>>()?;
+    let find = |decision: &str, path: &str| {
+        records
+            .iter()
+            .find(|r| r["decision"] == decision && r["path"] == path)
+            .cloned()
+            .unwrap_or_else(|| panic!("no {} record for {} in {}", decision, path, log))
+    };
+    assert_eq!(find("added", "new.txt")["level"], "info");
+    assert_eq!(find("deleted", "old.txt")["target"], "pulonia::decision");
+    assert!(
+        find("modified", "app.txt")["reason"]
+            .as_str()
+            .unwrap()
+            .starts_with("hash ")
+    );
+    assert_eq!(find("unchanged", "same.txt")["reason"], "same hash");
+    let ignored = find("ignored", "logs");
+    assert_eq!(ignored["level"], "debug");
+    assert_eq!(ignored["reason"], "matches .puloniaignore:1 rule 'logs/'");
+    assert!(ignored["time"].is_string());
+
+    fs::remove_dir_all(&root)?;
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn test_output_path_safety() -> Result<(), Box<dyn std::error::Error>> {
+    use std::os::unix::fs::symlink;
+
+    let root = create_workspace("output_path")?;
+    let outside = create_workspace("output_path_outside")?;
+
+    let before_dir = root.join("before");
+    fs::create_dir_all(&before_dir)?;
+    fs::write(before_dir.join("app.txt"), "app A")?;
+    let after_dir = root.join("after");
+    fs::create_dir_all(&after_dir)?;
+    fs::write(after_dir.join("app.txt"), "app B")?;
+    fs::create_dir_all(root.join("out"))?;
+    symlink(&outside, root.join("linked"))?;
+    symlink(outside.join("target.zip"), root.join("dangling.zip"))?;
+
+    // New files in the working directory or one of its sub-directories are accepted
+    for output in ["patch.zip", "out/patch.zip"] {
+        pulonia_in(&root)
+            .args(["-b", "before", "-a", "after", "-o", output])
+            .assert()
+            .success();
+        assert!(root.join(output).is_file(), "{} was not written", output);
+    }
+
+    // Parents resolving outside the working directory are rejected
+    let absolute = outside.join("absolute.zip");
+    for output in [
+        "../escape.zip",
+        "out/../../escape.zip",
+        "linked/patch.zip",
+        "dangling.zip",
+        absolute.to_str().unwrap(),
+    ] {
+        pulonia_in(&root)
+            .args(["-b", "before", "-a", "after", "-o", output])
+            .assert()
+            .code(2)
+            .stderr(predicate::str::contains("Output path is not safe"));
+    }
+    assert_eq!(fs::read_dir(&outside)?.count(), 0);
+
+    fs::remove_dir_all(&root)?;
+    fs::remove_dir_all(&outside)?;
+    Ok(())
+}
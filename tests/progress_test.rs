@@ -0,0 +1,77 @@
+// Progress reporting tests. The reporter is installed for the whole process, so
+// these tests live in their own test binary and run one at a time, away from the
+// in-process tests of integration_test.rs.
+use std::fs::{self, File};
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use pulonia::limits::ExtractLimits;
+use pulonia::progress::{self, Phase, ProgressReporter};
+use zip::write::FileOptions;
+
+#[derive(Default)]
+struct RecordingReporter {
+    events: Mutex<Vec<String>>,
+    bytes: AtomicU64,
+}
+
+impl ProgressReporter for RecordingReporter {
+    fn start(&self, phase: Phase, name: &str, total: Option<u64>) {
+        self.events
+            .lock()
+            .unwrap()
+            .push(format!("start {} {} {:?}", phase.name(), name, total));
+    }
+
+    fn entry(&self, phase: Phase, name: &str) {
+        self.events
+            .lock()
+            .unwrap()
+            .push(format!("entry {} {}", phase.name(), name));
+    }
+
+    fn advance(&self, _phase: Phase, bytes: u64) {
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+}
+
+#[test]
+fn test_progress_reporter() -> Result<(), Box<dyn std::error::Error>> {
+    // Input paths must be below the working directory
+    let root = std::env::current_dir()?.join(".test_temp_progress_reporter");
+    if root.exists() {
+        fs::remove_dir_all(&root)?;
+    }
+    fs::create_dir(&root)?;
+
+    let archive = root.join("progress.zip");
+    let mut zip = zip::ZipWriter::new(File::create(&archive)?);
+    zip.start_file("progress_app.txt", FileOptions::default())?;
+    zip.write_all(b"app")?;
+    zip.start_file("sub/progress_data.bin", FileOptions::default())?;
+    zip.write_all(&[7u8; 100_000])?;
+    zip.finish()?;
+
+    let reporter = Arc::new(RecordingReporter::default());
+    progress::set_reporter(reporter.clone());
+    let result = pulonia::compress::decompress(
+        archive.to_str().unwrap(),
+        root.join("out").to_str().unwrap(),
+        Some("zip"),
+        &ExtractLimits::default(),
+        None,
+    );
+    progress::clear_reporter();
+    result?;
+
+    // The zip central directory gives the expected size up front
+    let events = reporter.events.lock().unwrap().clone();
+    assert!(events.contains(&format!("start extract {} Some(100003)", archive.display())));
+    assert!(events.contains(&"entry extract progress_app.txt".to_string()));
+    assert!(events.contains(&"entry extract sub/progress_data.bin".to_string()));
+    assert!(reporter.bytes.load(Ordering::Relaxed) >= 100_003);
+
+    fs::remove_dir_all(&root)?;
+    Ok(())
+}